@@ -0,0 +1,228 @@
+use bounded_spsc_queue::Producer;
+
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::*;
+
+/// Everything a frontend can ask of the audio and dictionary handlers. Keyboard shortcuts and
+/// the other control surfaces are all expressed in terms of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    StartDsp,
+    StopDsp,
+    Reconstruct,
+    Play,
+    StopPlayback,
+    Export,
+    Clear,
+    ThresholdUp,
+    ThresholdDown,
+    DepthUp,
+    DepthDown,
+    ToggleLoop,
+    ToggleRecord,
+    ToggleHelp,
+}
+
+pub const ACTIONS: [Action; 14] = [
+    Action::StartDsp,
+    Action::StopDsp,
+    Action::Reconstruct,
+    Action::Play,
+    Action::StopPlayback,
+    Action::Export,
+    Action::Clear,
+    Action::ThresholdUp,
+    Action::ThresholdDown,
+    Action::DepthUp,
+    Action::DepthDown,
+    Action::ToggleLoop,
+    Action::ToggleRecord,
+    Action::ToggleHelp,
+];
+
+impl Action {
+    /// Name used in the config file
+    pub fn name(&self) -> &'static str {
+        use Action::*;
+        match *self {
+            StartDsp => "start_dsp",
+            StopDsp => "stop_dsp",
+            Reconstruct => "reconstruct",
+            Play => "play",
+            StopPlayback => "stop",
+            Export => "export",
+            Clear => "clear",
+            ThresholdUp => "threshold_up",
+            ThresholdDown => "threshold_down",
+            DepthUp => "depth_up",
+            DepthDown => "depth_down",
+            ToggleLoop => "toggle_loop",
+            ToggleRecord => "toggle_record",
+            ToggleHelp => "toggle_help",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTIONS.iter().find(|a| a.name() == name).cloned()
+    }
+
+    pub fn description(&self) -> &'static str {
+        use Action::*;
+        match *self {
+            StartDsp => "Start DSP",
+            StopDsp => "Stop DSP",
+            Reconstruct => "Reconstruct",
+            Play => "Play reconstruction",
+            StopPlayback => "Stop playback",
+            Export => "Export reconstruction",
+            Clear => "Clear captured input",
+            ThresholdUp => "Threshold +1",
+            ThresholdDown => "Threshold -1",
+            DepthUp => "Depth +1",
+            DepthDown => "Depth -1",
+            ToggleLoop => "Toggle looped playback",
+            ToggleRecord => "Toggle recording the input",
+            ToggleHelp => "Show/hide this help",
+        }
+    }
+}
+
+/// Requests from remote control surfaces, passed to whichever frontend is running so that it
+/// stays in step with them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Remote {
+    Perform(Action),
+    Threshold(usize),
+    Depth(usize),
+    /// Position in the playlist
+    Target(usize),
+}
+
+/// Sends actions on to the handlers and keeps track of the parameters the frontend displays
+pub struct Controller {
+    audio_commands_producer: Producer<AudioHandlerEvent>,
+    dictionary_commands_producer: mpsc::Sender<DictionaryHandlerEvent>,
+    pub threshold: usize,
+    pub depth: usize,
+    pub looping: bool,
+    pub recording: bool,
+    midi: Option<Arc<Mutex<MidiMap>>>,
+}
+
+impl Controller {
+    pub fn new(audio_commands_producer: Producer<AudioHandlerEvent>, dictionary_commands_producer: mpsc::Sender<DictionaryHandlerEvent>) -> Controller {
+        Controller {
+            audio_commands_producer: audio_commands_producer,
+            dictionary_commands_producer: dictionary_commands_producer,
+            threshold: DEFAULT_THRESHOLD,
+            depth: DEFAULT_DEPTH,
+            looping: false,
+            recording: false,
+            midi: None,
+        }
+    }
+
+    /// Lets the frontend start MIDI learn
+    pub fn with_midi(mut self, midi: Arc<Mutex<MidiMap>>) -> Controller {
+        self.midi = Some(midi);
+        self
+    }
+
+    /// Maps the next MIDI control that moves to `target`, an action name or "threshold" or "depth"
+    pub fn learn_midi<T>(&mut self, target: &str) -> Result<(), Error<T>> {
+        match self.midi {
+            Some(ref midi) => {
+                let mut midi = try!(midi.lock().map_err(|_| Error::String("MIDI map poisoned".to_string())));
+                midi.learn(target).map_err(Error::String)
+            }
+            None => Err(Error::String("MIDI isn't enabled".to_string())),
+        }
+    }
+
+    /// The target MIDI learn is waiting to assign, if any
+    pub fn midi_learning(&self) -> Option<String> {
+        self.midi.as_ref()
+            .and_then(|midi| midi.lock().ok())
+            .and_then(|midi| midi.learning().map(|s| s.to_string()))
+    }
+
+    pub fn perform<T>(&mut self, action: Action) -> Result<(), Error<T>> {
+        use Action::*;
+        match action {
+            StartDsp => self.audio(AudioHandlerEvent::Start),
+            StopDsp => self.audio(AudioHandlerEvent::Stop),
+            Reconstruct => self.dictionary(DictionaryHandlerEvent::Refresh),
+            Play => self.dictionary(DictionaryHandlerEvent::Play),
+            StopPlayback => self.dictionary(DictionaryHandlerEvent::Stop),
+            Export => self.dictionary(DictionaryHandlerEvent::Export(default_export_path())),
+            Clear => self.dictionary(DictionaryHandlerEvent::Clear),
+            ThresholdUp => { let x = self.threshold + 1; self.set_threshold(x) }
+            ThresholdDown => { let x = self.threshold.saturating_sub(1); self.set_threshold(x) }
+            DepthUp => { let x = self.depth + 1; self.set_depth(x) }
+            DepthDown => { let x = self.depth.saturating_sub(1); self.set_depth(x) }
+            ToggleLoop => {
+                self.looping = !self.looping;
+                let looping = self.looping;
+                self.dictionary(DictionaryHandlerEvent::SetLoop(looping))
+            }
+            ToggleRecord => {
+                self.recording = !self.recording;
+                let recording = self.recording;
+                self.dictionary(DictionaryHandlerEvent::SetRecording(recording))
+            }
+            // Purely a frontend concern
+            ToggleHelp => Ok(()),
+        }
+    }
+
+    pub fn remote<T>(&mut self, remote: Remote) -> Result<(), Error<T>> {
+        match remote {
+            Remote::Perform(action) => self.perform(action),
+            Remote::Threshold(x) => self.set_threshold(x),
+            Remote::Depth(x) => self.set_depth(x),
+            Remote::Target(x) => self.dictionary(DictionaryHandlerEvent::SetTarget(x)),
+        }
+    }
+
+    pub fn set_threshold<T>(&mut self, threshold: usize) -> Result<(), Error<T>> {
+        self.threshold = ::std::cmp::max(threshold, 1);
+        let threshold = self.threshold;
+        self.dictionary(DictionaryHandlerEvent::SetThreshold(threshold))
+    }
+
+    pub fn set_depth<T>(&mut self, depth: usize) -> Result<(), Error<T>> {
+        self.depth = ::std::cmp::max(depth, 1);
+        let depth = self.depth;
+        self.dictionary(DictionaryHandlerEvent::SetDepth(depth))
+    }
+
+    pub fn audio<T>(&mut self, event: AudioHandlerEvent) -> Result<(), Error<T>> {
+        self.audio_commands_producer.push(event);
+        Ok(())
+    }
+
+    pub fn dictionary<T>(&mut self, event: DictionaryHandlerEvent) -> Result<(), Error<T>> {
+        self.dictionary_commands_producer.send(event)
+            .map_err(|_| Error::String("Cannot send".to_string()))
+    }
+
+    /// Replaces live input with a WAV file, streamed at `speed` times realtime
+    pub fn load_source<T>(&mut self, path: PathBuf, speed: f64) -> Result<(), Error<T>> {
+        self.audio(AudioHandlerEvent::LoadSource(path, speed))
+    }
+
+    /// Tells both handlers to shut down
+    pub fn quit(&mut self) {
+        self.audio_commands_producer.push(AudioHandlerEvent::Quit);
+        self.dictionary_commands_producer.send(DictionaryHandlerEvent::Quit);
+    }
+}
+
+/// Exports go to the working directory, named by the time they were made
+fn default_export_path() -> PathBuf {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    PathBuf::from(format!("reconstruction-{}.wav", secs))
+}
@@ -0,0 +1,873 @@
+use hound;
+use portaudio::{self, Continue, DeviceIndex, DuplexStreamCallbackArgs, DuplexStreamSettings, InputStreamCallbackArgs,
+                InputStreamSettings, OutputStreamCallbackArgs, OutputStreamSettings, PortAudio, StreamParameters};
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use super::*;
+
+/// Moves one buffer of audio: reads the input, fills the output. Both are interleaved, with
+/// the channel counts given by `AudioBackend::channels`, and hold the same number of frames,
+/// normally the frames per buffer the stream was opened with.
+pub type Callback = Box<FnMut(&[f32], &mut [f32]) + Send>;
+
+/// Most input channels a stream is opened with, however many the device has
+pub const MAX_INPUT_CHANNELS: usize = 16;
+/// Output is stereo when the device allows it
+pub const OUTPUT_CHANNELS: usize = 2;
+/// Limits on frames per buffer
+pub const MIN_FRAMES_PER_BUFFER: u32 = 16;
+pub const MAX_FRAMES_PER_BUFFER: u32 = 8192;
+/// Limits on how many blocks of input can queue up for the dictionary. The queue is allocated
+/// up front, so the top one keeps it to 64 MB.
+pub const MIN_QUEUE_DEPTH: usize = 16;
+pub const MAX_QUEUE_DEPTH: usize = 262144;
+
+/// Checks frames per buffer and suggested latency (in seconds) are within reason
+fn validate_buffer<T>(frames_per_buffer: u32, latency: Option<f64>) -> Result<(), Error<T>> {
+    if frames_per_buffer < MIN_FRAMES_PER_BUFFER || frames_per_buffer > MAX_FRAMES_PER_BUFFER {
+        return Err(Error::String(format!("frames per buffer must be between {} and {}", MIN_FRAMES_PER_BUFFER, MAX_FRAMES_PER_BUFFER)));
+    }
+    match latency {
+        Some(l) if l <= 0. || l > 1. => Err(Error::String(format!("latency of {} s is out of range", l))),
+        _ => Ok(()),
+    }
+}
+
+/// Brings a queue depth within the limits, saying so if it had to
+pub fn clamp_queue_depth(depth: usize) -> usize {
+    let clamped = depth.max(MIN_QUEUE_DEPTH).min(MAX_QUEUE_DEPTH);
+    if clamped != depth {
+        println!("queue depth must be between {} and {} blocks, using {}", MIN_QUEUE_DEPTH, MAX_QUEUE_DEPTH, clamped);
+    }
+    clamped
+}
+
+// Round trip times are shared with the callbacks as whole microseconds, 0 for not known yet
+fn store_seconds(cell: &AtomicUsize, seconds: f64) {
+    if seconds > 0. {
+        cell.store((seconds * 1e6) as usize, Ordering::SeqCst);
+    }
+}
+
+fn load_seconds(cell: &AtomicUsize) -> Option<f64> {
+    match cell.load(Ordering::SeqCst) {
+        0 => None,
+        us => Some(us as f64 / 1e6),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    PortAudio,
+    File,
+}
+
+/// What an open stream actually ended up with, which can differ from what was asked for
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub in_device: DeviceIndex,
+    pub out_device: DeviceIndex,
+    pub sample_rate: f64,
+    /// In seconds
+    pub input_latency: f64,
+    pub output_latency: f64,
+}
+
+/// A device as offered in the GUI
+#[derive(Debug, Clone)]
+pub struct DeviceEntry {
+    pub index: DeviceIndex,
+    pub name: String,
+    pub input_channels: usize,
+    pub output_channels: usize,
+    pub default_sample_rate: f64,
+}
+
+impl DeviceEntry {
+    pub fn is_input(&self) -> bool {
+        self.input_channels > 0
+    }
+
+    pub fn is_output(&self) -> bool {
+        self.output_channels > 0
+    }
+
+    /// Name followed by what the device can do, e.g. "Built-in (2 in, 2 out, 44100 Hz)"
+    pub fn label(&self) -> String {
+        format!("{} ({} in, {} out, {} Hz)", self.name, self.input_channels, self.output_channels, self.default_sample_rate)
+    }
+}
+
+/// Where `audio_handler` gets its audio from and sends it to
+pub trait AudioBackend {
+    /// Devices to offer in the GUI
+    fn devices<T>(&self) -> Result<Vec<DeviceEntry>, Error<T>>;
+    /// Looks for devices again, picking up any plugged in since the last scan. Devices in use
+    /// are kept if they're still there. Only possible while no stream is open.
+    fn rescan<T>(&mut self) -> Result<(), Error<T>>;
+    /// Default input and output devices
+    fn default_devices<T>(&self) -> Result<(DeviceIndex, DeviceIndex), Error<T>>;
+    /// Devices the next stream will be opened on
+    fn current_devices(&self) -> (DeviceIndex, DeviceIndex);
+    fn set_in_device<T>(&mut self, idx: DeviceIndex) -> Result<(), Error<T>>;
+    fn set_out_device<T>(&mut self, idx: DeviceIndex) -> Result<(), Error<T>>;
+    /// Input and output channels the next stream will have
+    fn channels(&self) -> (usize, usize);
+    fn sample_rate(&self) -> f64;
+    /// Takes effect the next time a stream is opened
+    fn set_sample_rate<T>(&mut self, rate: f64) -> Result<(), Error<T>>;
+    /// Frames per buffer and suggested latency in seconds
+    fn buffer(&self) -> (u32, Option<f64>);
+    /// Sets the frames per callback and the suggested latency, or the devices' own low latency
+    /// default if `None`. Takes effect the next time a stream is opened.
+    fn set_buffer<T>(&mut self, frames_per_buffer: u32, latency: Option<f64>) -> Result<(), Error<T>>;
+    /// Time from a sample arriving at the input to it leaving the output, as measured by the
+    /// running stream, if the backend can tell
+    fn round_trip(&self) -> Option<f64>;
+    /// Opens a stream that will feed `callback`. It doesn't run until `start`.
+    fn open<T>(&mut self, callback: Callback) -> Result<(), Error<T>>;
+    fn is_open(&self) -> bool;
+    /// `None` when there's no stream open
+    fn stream_info(&self) -> Option<StreamInfo>;
+    fn start<T>(&mut self) -> Result<(), Error<T>>;
+    fn stop<T>(&mut self) -> Result<(), Error<T>>;
+    /// Stops and drops the stream, along with its callback
+    fn close<T>(&mut self) -> Result<(), Error<T>>;
+}
+
+/// Either one stream doing both, or one each when the devices can't share a duplex stream
+enum PortAudioStream {
+    Duplex(portaudio::Stream<portaudio::NonBlocking, portaudio::Duplex<f32, f32>>),
+    /// Input reaches the output stream's callback through a drift bridge
+    Split(portaudio::Stream<portaudio::NonBlocking, portaudio::Input<f32>>,
+          portaudio::Stream<portaudio::NonBlocking, portaudio::Output<f32>>),
+}
+
+/// Streams on sound cards. Input and output share a duplex stream where the devices allow
+/// it, and get a stream each otherwise.
+pub struct PortAudioBackend {
+    // Empty if starting it again in a rescan failed, until a rescan manages it
+    pa: Option<PortAudio>,
+    settings: DuplexStreamSettings<f32, f32>,
+    // Suggested latency, in place of the devices' default
+    latency: Option<f64>,
+    // Buffer size and latency for the output when it needs a stream of its own, in place of
+    // the ones the input uses
+    output_frames: Option<u32>,
+    output_latency: Option<f64>,
+    stream: Option<PortAudioStream>,
+    // Only there while input and output run as separate streams
+    bridge_stats: Option<Arc<BridgeStats>>,
+    round_trip: Arc<AtomicUsize>,
+}
+
+impl PortAudioBackend {
+    pub fn new<T>(sample_rate: f64) -> Result<PortAudioBackend, Error<T>> {
+        let pa = try!(PortAudio::new());
+        let in_info = try!(pa.device_info(try!(pa.default_input_device())));
+        let out_info = try!(pa.device_info(try!(pa.default_output_device())));
+        let settings = try!(pa.default_duplex_stream_settings(input_channels(in_info.max_input_channels),
+                                                              output_channels(out_info.max_output_channels),
+                                                              sample_rate, BLOCK_SIZE as u32));
+        Ok(PortAudioBackend {
+            pa: Some(pa),
+            settings: settings,
+            latency: None,
+            output_frames: None,
+            output_latency: None,
+            stream: None,
+            bridge_stats: None,
+            round_trip: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Sets the frames per callback and suggested latency for the output stream, for when the
+    /// devices can't share a duplex stream. Either left as `None` follows the input's.
+    /// Takes effect the next time a stream is opened.
+    pub fn set_output_buffer<T>(&mut self, frames_per_buffer: Option<u32>, latency: Option<f64>) -> Result<(), Error<T>> {
+        try!(validate_buffer(frames_per_buffer.unwrap_or(self.settings.frames_per_buffer), latency));
+        self.output_frames = frames_per_buffer;
+        self.output_latency = latency;
+        Ok(())
+    }
+
+    /// Frames per callback of the input and output streams when they run separately
+    fn split_frames(&self) -> (u32, u32) {
+        let in_frames = self.settings.frames_per_buffer;
+        (in_frames, self.output_frames.unwrap_or(in_frames))
+    }
+
+    fn pa(&self) -> Result<&PortAudio, String> {
+        self.pa.as_ref().ok_or("PortAudio isn't running, rescan devices to start it again".to_string())
+    }
+
+    /// Sets up the current devices again, picking up their default latencies if none is set,
+    /// and checks they'll still take the settings
+    fn check_devices<T>(&mut self) -> Result<(), Error<T>> {
+        let (in_device, out_device) = self.current_devices();
+        try!(self.set_in_device(in_device));
+        try!(self.set_out_device(out_device));
+        try!(try!(self.pa()).is_input_format_supported(self.settings.in_params, self.settings.sample_rate));
+        try!(try!(self.pa()).is_output_format_supported(self.settings.out_params, self.settings.sample_rate));
+        Ok(())
+    }
+}
+
+fn input_channels(max: i32) -> i32 {
+    ::std::cmp::min(::std::cmp::max(max, 1), MAX_INPUT_CHANNELS as i32)
+}
+
+fn output_channels(max: i32) -> i32 {
+    ::std::cmp::min(::std::cmp::max(max, 1), OUTPUT_CHANNELS as i32)
+}
+
+impl AudioBackend for PortAudioBackend {
+    fn devices<T>(&self) -> Result<Vec<DeviceEntry>, Error<T>> {
+        Ok(try!(try!(self.pa()).devices()).filter_map(|d| {
+            match d {
+                Ok((index, info)) => Some(DeviceEntry {
+                    index: index,
+                    name: info.name.to_string(),
+                    input_channels: ::std::cmp::max(info.max_input_channels, 0) as usize,
+                    output_channels: ::std::cmp::max(info.max_output_channels, 0) as usize,
+                    default_sample_rate: info.default_sample_rate,
+                }),
+                Err(e) => {
+                    println!("skipping a device that can't be queried: {}", e);
+                    None
+                }
+            }
+        }).collect())
+    }
+
+    fn rescan<T>(&mut self) -> Result<(), Error<T>> {
+        if self.stream.is_some() {
+            return Err(Error::String("stop DSP before rescanning devices".to_string()));
+        }
+        let in_name = self.pa.as_ref().and_then(|pa| pa.device_info(self.settings.in_params.device).ok())
+            .map(|i| i.name.to_string());
+        let out_name = self.pa.as_ref().and_then(|pa| pa.device_info(self.settings.out_params.device).ok())
+            .map(|i| i.name.to_string());
+
+        // PortAudio only enumerates devices when it starts up, so shut it down completely
+        // before starting it again. If it won't start, the next rescan tries again.
+        self.pa = None;
+        self.pa = Some(try!(PortAudio::new()));
+
+        // Indices can shift when devices come and go, so find the ones in use by name
+        let devices = try!(self.devices());
+        let (default_in, default_out) = try!(self.default_devices());
+        let in_device = devices.iter()
+            .find(|d| d.is_input() && Some(&d.name) == in_name.as_ref())
+            .map(|d| d.index).unwrap_or(default_in);
+        let out_device = devices.iter()
+            .find(|d| d.is_output() && Some(&d.name) == out_name.as_ref())
+            .map(|d| d.index).unwrap_or(default_out);
+        try!(self.set_in_device(in_device));
+        try!(self.set_out_device(out_device));
+        println!("found {} devices", devices.len());
+        Ok(())
+    }
+
+    fn default_devices<T>(&self) -> Result<(DeviceIndex, DeviceIndex), Error<T>> {
+        Ok((try!(try!(self.pa()).default_input_device()), try!(try!(self.pa()).default_output_device())))
+    }
+
+    fn current_devices(&self) -> (DeviceIndex, DeviceIndex) {
+        (self.settings.in_params.device, self.settings.out_params.device)
+    }
+
+    fn set_in_device<T>(&mut self, idx: DeviceIndex) -> Result<(), Error<T>> {
+        let info = try!(try!(self.pa()).device_info(idx));
+        println!("Setting input device to {}", info.name);
+        let latency = self.latency.unwrap_or(info.default_low_input_latency);
+        self.settings.in_params = StreamParameters::new(idx, input_channels(info.max_input_channels), true, latency);
+        Ok(())
+    }
+
+    fn set_out_device<T>(&mut self, idx: DeviceIndex) -> Result<(), Error<T>> {
+        let info = try!(try!(self.pa()).device_info(idx));
+        println!("Setting output device to {}", info.name);
+        let latency = self.latency.unwrap_or(info.default_low_output_latency);
+        self.settings.out_params = StreamParameters::new(idx, output_channels(info.max_output_channels), true, latency);
+        Ok(())
+    }
+
+    fn channels(&self) -> (usize, usize) {
+        (self.settings.in_params.channel_count as usize, self.settings.out_params.channel_count as usize)
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.settings.sample_rate
+    }
+
+    fn set_sample_rate<T>(&mut self, rate: f64) -> Result<(), Error<T>> {
+        // Check with the devices first, rather than finding out when the stream won't open.
+        // Each only has to manage it on its own, since they can always run separately.
+        try!(try!(self.pa()).is_input_format_supported(self.settings.in_params, rate));
+        try!(try!(self.pa()).is_output_format_supported(self.settings.out_params, rate));
+        println!("Setting sample rate to {} Hz", rate);
+        self.settings.sample_rate = rate;
+        Ok(())
+    }
+
+    fn buffer(&self) -> (u32, Option<f64>) {
+        (self.settings.frames_per_buffer, self.latency)
+    }
+
+    fn set_buffer<T>(&mut self, frames_per_buffer: u32, latency: Option<f64>) -> Result<(), Error<T>> {
+        try!(validate_buffer(frames_per_buffer, latency));
+        let (old_settings, old_latency) = (self.settings, self.latency);
+
+        self.latency = latency;
+        self.settings.frames_per_buffer = frames_per_buffer;
+        if let Err(e) = self.check_devices::<T>() {
+            self.settings = old_settings;
+            self.latency = old_latency;
+            return Err(e);
+        }
+        println!("Using {} frames per buffer, latency {}", frames_per_buffer,
+                 latency.map(|l| format!("{} ms", l * 1000.)).unwrap_or("default".to_string()));
+        Ok(())
+    }
+
+    fn round_trip(&self) -> Option<f64> {
+        if self.stream.is_some() { load_seconds(&self.round_trip) } else { None }
+    }
+
+    fn open<T>(&mut self, mut callback: Callback) -> Result<(), Error<T>> {
+        let settings = self.settings;
+        let round_trip = self.round_trip.clone();
+        round_trip.store(0, Ordering::SeqCst);
+
+        let duplex = try!(self.pa()).is_duplex_format_supported(settings.in_params, settings.out_params, settings.sample_rate).is_ok();
+        if duplex {
+            println!("opening stream with {:?}", &settings);
+            let callback = move |DuplexStreamCallbackArgs { in_buffer, out_buffer, time, .. }| {
+                store_seconds(&round_trip, time.output_dac - time.input_adc);
+                callback(in_buffer, out_buffer);
+                Continue
+            };
+            // settings is copy, so sending it is totally okay in this instance
+            self.stream = Some(PortAudioStream::Duplex(try!(try!(self.pa()).open_non_blocking_stream(settings, callback))));
+            return Ok(());
+        }
+
+        let (in_frames, out_frames) = self.split_frames();
+        let mut out_params = settings.out_params;
+        if let Some(latency) = self.output_latency {
+            out_params.suggested_latency = latency;
+        }
+        println!("devices can't share a stream, opening input ({} frames) and output ({} frames) separately", in_frames, out_frames);
+        let in_channels = settings.in_params.channel_count as usize;
+        let (bridge_in, mut bridge_out) = drift_bridge(in_channels, in_frames as usize, out_frames as usize);
+        self.bridge_stats = Some(bridge_out.stats());
+        // The input stream's share of the round trip, for the output stream to add its own to
+        let input_delay = Arc::new(AtomicUsize::new(0));
+        let callback_input_delay = input_delay.clone();
+
+        let in_settings = InputStreamSettings::new(settings.in_params, settings.sample_rate, in_frames);
+        let input = try!(try!(self.pa()).open_non_blocking_stream(in_settings, move |InputStreamCallbackArgs { buffer, time, .. }| {
+            store_seconds(&callback_input_delay, time.current - time.buffer_adc);
+            bridge_in.push(buffer);
+            Continue
+        }));
+
+        // The output stream drives the callback, so it still gets input and output together
+        let out_settings = OutputStreamSettings::new(out_params, settings.sample_rate, out_frames);
+        let mut in_buffer = vec![0f32; out_frames as usize * in_channels];
+        let sample_rate = settings.sample_rate;
+        let output = try!(try!(self.pa()).open_non_blocking_stream(out_settings, move |OutputStreamCallbackArgs { buffer, frames, time, .. }| {
+            if let Some(input_delay) = load_seconds(&input_delay) {
+                let bridged = bridge_out.queued() as f64 / sample_rate;
+                store_seconds(&round_trip, input_delay + bridged + time.buffer_dac - time.current);
+            }
+            let in_buffer = &mut in_buffer[..frames * in_channels];
+            bridge_out.pull(in_buffer);
+            callback(in_buffer, buffer);
+            Continue
+        }));
+
+        self.stream = Some(PortAudioStream::Split(input, output));
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        let (sample_rate, input_latency, output_latency) = match self.stream {
+            Some(PortAudioStream::Duplex(ref s)) => {
+                let info = s.info();
+                (info.sample_rate, info.input_latency, info.output_latency)
+            }
+            Some(PortAudioStream::Split(ref input, ref output)) => {
+                let (in_info, out_info) = (input.info(), output.info());
+                // Input waits in the bridge on its way through
+                let (in_frames, out_frames) = self.split_frames();
+                let bridged = bridge_target(::std::cmp::max(in_frames, out_frames) as usize) as f64 / in_info.sample_rate;
+                (out_info.sample_rate, in_info.input_latency + bridged, out_info.output_latency)
+            }
+            None => return None,
+        };
+        Some(StreamInfo {
+            in_device: self.settings.in_params.device,
+            out_device: self.settings.out_params.device,
+            sample_rate: sample_rate,
+            input_latency: input_latency,
+            output_latency: output_latency,
+        })
+    }
+
+    fn start<T>(&mut self) -> Result<(), Error<T>> {
+        match self.stream {
+            Some(PortAudioStream::Duplex(ref mut s)) => { try!(s.start()); Ok(()) }
+            Some(PortAudioStream::Split(ref mut input, ref mut output)) => {
+                try!(input.start());
+                try!(output.start());
+                Ok(())
+            }
+            None => Err(Error::String("no stream open".to_string())),
+        }
+    }
+
+    fn stop<T>(&mut self) -> Result<(), Error<T>> {
+        match self.stream {
+            Some(PortAudioStream::Duplex(ref mut s)) => { try!(s.stop()); Ok(()) }
+            Some(PortAudioStream::Split(ref mut input, ref mut output)) => {
+                try!(output.stop());
+                try!(input.stop());
+                Ok(())
+            }
+            None => Err(Error::String("no stream open".to_string())),
+        }
+    }
+
+    fn close<T>(&mut self) -> Result<(), Error<T>> {
+        match self.stream.take() {
+            Some(PortAudioStream::Duplex(mut s)) => {
+                if try!(s.is_active()) {
+                    try!(s.stop());
+                }
+                try!(s.close());
+            }
+            Some(PortAudioStream::Split(mut input, mut output)) => {
+                if try!(output.is_active()) {
+                    try!(output.stop());
+                }
+                try!(output.close());
+                if try!(input.is_active()) {
+                    try!(input.stop());
+                }
+                try!(input.close());
+            }
+            None => { }
+        }
+        if let Some(stats) = self.bridge_stats.take() {
+            let (overflows, underruns) = (stats.overflows.load(Ordering::SeqCst), stats.underruns.load(Ordering::SeqCst));
+            if overflows > 0 || underruns > 0 {
+                println!("warning: the input bridge overflowed {} times and ran dry {} times", overflows, underruns);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything the file stream thread needs, handed back when it stops so it can pick up where
+/// it left off
+struct FileStream {
+    callback: Callback,
+    /// Interleaved, `in_channels` wide
+    input: Vec<f32>,
+    in_channels: usize,
+    frames_per_buffer: usize,
+    /// In frames
+    position: usize,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+}
+
+/// Stands in for a sound card without needing one: input comes from a WAV file (or silence),
+/// with as many channels as the file has, and output goes to a stereo WAV file (or nowhere).
+/// Runs at `speed` times realtime; a speed of 0 goes as fast as possible.
+pub struct FileBackend {
+    input_path: Option<PathBuf>,
+    output_path: Option<PathBuf>,
+    in_channels: usize,
+    speed: f64,
+    sample_rate: f64,
+    frames_per_buffer: u32,
+    stream: Option<FileStream>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<FileStream>>,
+}
+
+impl FileBackend {
+    pub fn new(input_path: Option<PathBuf>, output_path: Option<PathBuf>, speed: f64, sample_rate: f64) -> FileBackend {
+        // Silence never runs out, so don't let it spin
+        let speed = if speed <= 0. && input_path.is_none() { 1. } else { speed };
+        // Only the header is needed for now; a missing file is reported when the stream opens
+        let in_channels = input_path.as_ref()
+            .and_then(|path| hound::WavReader::open(path).ok())
+            .map(|reader| reader.spec().channels as usize)
+            .unwrap_or(1);
+        FileBackend {
+            input_path: input_path,
+            output_path: output_path,
+            in_channels: in_channels,
+            speed: speed,
+            sample_rate: sample_rate,
+            frames_per_buffer: BLOCK_SIZE as u32,
+            stream: None,
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn devices<T>(&self) -> Result<Vec<DeviceEntry>, Error<T>> {
+        let input = self.input_path.as_ref()
+            .map(|p| format!("File: {}", p.display()))
+            .unwrap_or("Silence".to_string());
+        let output = self.output_path.as_ref()
+            .map(|p| format!("File: {}", p.display()))
+            .unwrap_or("Nowhere".to_string());
+        Ok(vec![
+            DeviceEntry { index: DeviceIndex(0), name: input, input_channels: self.in_channels, output_channels: 0, default_sample_rate: self.sample_rate },
+            DeviceEntry { index: DeviceIndex(1), name: output, input_channels: 0, output_channels: OUTPUT_CHANNELS, default_sample_rate: self.sample_rate },
+        ])
+    }
+
+    fn rescan<T>(&mut self) -> Result<(), Error<T>> {
+        Ok(())
+    }
+
+    fn default_devices<T>(&self) -> Result<(DeviceIndex, DeviceIndex), Error<T>> {
+        Ok((DeviceIndex(0), DeviceIndex(1)))
+    }
+
+    fn current_devices(&self) -> (DeviceIndex, DeviceIndex) {
+        (DeviceIndex(0), DeviceIndex(1))
+    }
+
+    fn set_in_device<T>(&mut self, _: DeviceIndex) -> Result<(), Error<T>> {
+        println!("the file backend only has one input");
+        Ok(())
+    }
+
+    fn set_out_device<T>(&mut self, _: DeviceIndex) -> Result<(), Error<T>> {
+        println!("the file backend only has one output");
+        Ok(())
+    }
+
+    fn channels(&self) -> (usize, usize) {
+        (self.in_channels, OUTPUT_CHANNELS)
+    }
+
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn set_sample_rate<T>(&mut self, rate: f64) -> Result<(), Error<T>> {
+        if rate <= 0. {
+            return Err(Error::String(format!("invalid sample rate {}", rate)));
+        }
+        self.sample_rate = rate;
+        Ok(())
+    }
+
+    fn buffer(&self) -> (u32, Option<f64>) {
+        (self.frames_per_buffer, None)
+    }
+
+    fn set_buffer<T>(&mut self, frames_per_buffer: u32, latency: Option<f64>) -> Result<(), Error<T>> {
+        // There's no device, so no latency to speak of
+        try!(validate_buffer(frames_per_buffer, latency));
+        self.frames_per_buffer = frames_per_buffer;
+        Ok(())
+    }
+
+    fn round_trip(&self) -> Option<f64> {
+        None
+    }
+
+    fn open<T>(&mut self, callback: Callback) -> Result<(), Error<T>> {
+        try!(self.stop::<T>());
+
+        let input = match self.input_path {
+            Some(ref path) => {
+                let (samples, channels, file_rate) = try!(read_wav(path));
+                self.in_channels = channels;
+                if file_rate as f64 == self.sample_rate {
+                    samples
+                } else {
+                    println!("Resampling input from {} Hz to {} Hz", file_rate, self.sample_rate);
+                    resample_interleaved(&samples[..], channels, file_rate as f64, self.sample_rate)
+                }
+            }
+            None => Vec::new(),
+        };
+
+        let writer = match self.output_path {
+            Some(ref path) => {
+                let spec = hound::WavSpec {
+                    channels: OUTPUT_CHANNELS as u16,
+                    sample_rate: self.sample_rate as u32,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                };
+                Some(try!(hound::WavWriter::create(path, spec)
+                          .map_err(|e| Error::String(format!("cannot create {}: {}", path.display(), e)))))
+            }
+            None => None,
+        };
+
+        self.stream = Some(FileStream {
+            callback: callback,
+            input: input,
+            in_channels: self.in_channels,
+            frames_per_buffer: self.frames_per_buffer as usize,
+            position: 0,
+            writer: writer,
+        });
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.stream.is_some() || self.thread.is_some()
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        if !self.is_open() {
+            return None;
+        }
+        // Buffers go straight from the file to the callback and out again
+        let block = self.frames_per_buffer as f64 / self.sample_rate;
+        Some(StreamInfo {
+            in_device: DeviceIndex(0),
+            out_device: DeviceIndex(1),
+            sample_rate: self.sample_rate,
+            input_latency: block,
+            output_latency: block,
+        })
+    }
+
+    fn start<T>(&mut self) -> Result<(), Error<T>> {
+        if self.thread.is_some() {
+            if self.running.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            // The input ran out, so take the stream back and play the file again from the top
+            try!(self.stop::<T>());
+            if let Some(ref mut stream) = self.stream {
+                if stream.position * stream.in_channels >= stream.input.len() {
+                    stream.position = 0;
+                }
+            }
+        }
+        let mut stream = try!(self.stream.take().ok_or(Error::String("no stream open".to_string())));
+        let running = self.running.clone();
+        let has_input = self.input_path.is_some();
+        let block_duration = if self.speed > 0. {
+            Some(seconds_duration(self.frames_per_buffer as f64 / (self.sample_rate * self.speed)))
+        } else {
+            None
+        };
+
+        running.store(true, Ordering::SeqCst);
+        self.thread = Some(thread::spawn(move || {
+            let mut in_buffer = vec![0f32; stream.frames_per_buffer * stream.in_channels];
+            let mut out_buffer = vec![0f32; stream.frames_per_buffer * OUTPUT_CHANNELS];
+            let mut next_block = Instant::now();
+
+            while running.load(Ordering::SeqCst) {
+                let start = stream.position * stream.in_channels;
+                if has_input && start >= stream.input.len() {
+                    println!("end of input file");
+                    break;
+                }
+
+                for (i, s) in in_buffer.iter_mut().enumerate() {
+                    *s = stream.input.get(start + i).cloned().unwrap_or(0.);
+                }
+                stream.position += stream.frames_per_buffer;
+
+                (stream.callback)(&in_buffer[..], &mut out_buffer[..]);
+
+                if let Some(ref mut writer) = stream.writer {
+                    for s in out_buffer.iter() {
+                        if writer.write_sample(*s).is_err() {
+                            println!("warning: could not write output sample");
+                            break;
+                        }
+                    }
+                }
+
+                if let Some(duration) = block_duration {
+                    next_block += duration;
+                    let now = Instant::now();
+                    if next_block > now {
+                        thread::sleep(next_block - now);
+                    }
+                }
+            }
+
+            running.store(false, Ordering::SeqCst);
+            stream
+        }));
+        Ok(())
+    }
+
+    fn stop<T>(&mut self) -> Result<(), Error<T>> {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let stream = try!(thread.join().map_err(|_| Error::String("file stream thread panicked".to_string())));
+            self.stream = Some(stream);
+        }
+        Ok(())
+    }
+
+    fn close<T>(&mut self) -> Result<(), Error<T>> {
+        try!(self.stop::<T>());
+        self.stream = None;
+        Ok(())
+    }
+}
+
+/// Resamples each channel of an interleaved buffer separately
+fn resample_interleaved(samples: &[f32], channels: usize, from: f64, to: f64) -> Vec<f32> {
+    let resampled: Vec<Vec<f64>> = (0..channels).map(|c| {
+        let channel: Vec<f64> = samples.chunks(channels).map(|frame| frame[c] as f64).collect();
+        resample(&channel[..], from, to)
+    }).collect();
+    let frames = resampled.iter().map(|c| c.len()).min().unwrap_or(0);
+    (0..frames).flat_map(|i| resampled.iter().map(move |c| c[i] as f32)).collect()
+}
+
+/// Reads a WAV file into memory as interleaved floats. Returns the samples, the number of
+/// channels and the file's sample rate.
+pub fn read_wav<T>(path: &Path) -> Result<(Vec<f32>, usize, u32), Error<T>> {
+    let mut reader = try!(hound::WavReader::open(path)
+                          .map_err(|e| Error::String(format!("cannot open {}: {}", path.display(), e))));
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().map(|s| s.unwrap_or(0.)).collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|s| s.unwrap_or(0) as f32 / scale).collect()
+        }
+    };
+
+    Ok((samples, spec.channels as usize, spec.sample_rate))
+}
+
+/// Reads a WAV file into memory, mixing it down to mono floats. Returns the samples and the
+/// file's sample rate.
+pub fn read_wav_mono<T>(path: &Path) -> Result<(Vec<f32>, u32), Error<T>> {
+    let (samples, channels, sample_rate) = try!(read_wav(path));
+    let mono = samples.chunks(channels)
+        .map(|frame| frame.iter().fold(0., |acc, s| acc + s) / channels as f32)
+        .collect();
+    Ok((mono, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const RATE: f64 = 8000.;
+    const FRAMES: usize = 1000;
+
+    // Stereo input, the left channel rising and the right falling
+    fn write_input(path: &Path) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for n in 0..FRAMES {
+            writer.write_sample(n as f32 / FRAMES as f32).unwrap();
+            writer.write_sample(-(n as f32) / FRAMES as f32).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    // Opens the backend with a callback that passes on everything it's given and plays the
+    // left input channel on both outputs
+    fn open(backend: &mut FileBackend) -> mpsc::Receiver<Vec<f32>> {
+        let (input_prod, input_recv) = mpsc::channel();
+        backend.open::<()>(Box::new(move |in_buffer: &[f32], out_buffer: &mut [f32]| {
+            input_prod.send(in_buffer.to_vec()).ok();
+            for (frame, input) in out_buffer.chunks_mut(OUTPUT_CHANNELS).zip(in_buffer.chunks(2)) {
+                for s in frame.iter_mut() {
+                    *s = input[0];
+                }
+            }
+        })).unwrap();
+        input_recv
+    }
+
+    fn run_to_end(backend: &mut FileBackend) {
+        backend.start::<()>().unwrap();
+        let mut waited = 0;
+        while backend.running.load(Ordering::SeqCst) {
+            assert!(waited < 5000, "the file backend never reached the end of its input");
+            thread::sleep(Duration::from_millis(1));
+            waited += 1;
+        }
+    }
+
+    #[test]
+    fn plays_a_file_through_the_callback() {
+        let dir = ScratchDir::new("file-backend");
+        write_input(&dir.join("in.wav"));
+        let mut backend = FileBackend::new(Some(dir.join("in.wav")), Some(dir.join("out.wav")), 0., RATE);
+        assert_eq!(backend.channels(), (2, OUTPUT_CHANNELS));
+        let input_recv = open(&mut backend);
+        run_to_end(&mut backend);
+        backend.close::<()>().unwrap();
+
+        // The last block runs past the end of the file and is filled out with silence
+        let blocks = (FRAMES + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let input: Vec<f32> = input_recv.try_iter().flat_map(|b| b.into_iter()).collect();
+        assert_eq!(input.len(), blocks * BLOCK_SIZE * 2);
+        assert_eq!(input[2 * 10], 10. / FRAMES as f32);
+        assert_eq!(input[2 * 10 + 1], -10. / FRAMES as f32);
+        assert!(input[2 * FRAMES..].iter().all(|s| *s == 0.));
+
+        let reader = hound::WavReader::open(dir.join("out.wav")).unwrap();
+        assert_eq!(reader.spec().channels as usize, OUTPUT_CHANNELS);
+        assert_eq!(reader.duration() as usize, blocks * BLOCK_SIZE);
+        let (output, _, _) = read_wav::<()>(&dir.join("out.wav")).unwrap();
+        assert_eq!(output[2 * 10], input[2 * 10]);
+        assert_eq!(output[2 * 10 + 1], input[2 * 10]);
+    }
+
+    #[test]
+    fn starts_again_from_the_top_once_the_input_runs_out() {
+        let dir = ScratchDir::new("file-backend-restart");
+        write_input(&dir.join("in.wav"));
+        let mut backend = FileBackend::new(Some(dir.join("in.wav")), None, 0., RATE);
+        let input_recv = open(&mut backend);
+        run_to_end(&mut backend);
+        let first: Vec<Vec<f32>> = input_recv.try_iter().collect();
+        run_to_end(&mut backend);
+        let second: Vec<Vec<f32>> = input_recv.try_iter().collect();
+        backend.close::<()>().unwrap();
+        assert_eq!(first, second);
+        assert!(!backend.is_open());
+    }
+}
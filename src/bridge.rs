@@ -0,0 +1,232 @@
+use bounded_spsc_queue::{self, Consumer, Producer};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::*;
+
+/// Input frames the output side tries to keep queued for callbacks of the given size: enough
+/// to ride out one side's callback arriving late, without adding much latency
+pub fn bridge_target(frames_per_buffer: usize) -> usize {
+    ::std::cmp::max(4 * BLOCK_SIZE, 2 * frames_per_buffer)
+}
+
+/// How often a bridge has had to drop input or give silence. It's counted in the callbacks
+/// and reported from outside them, since printing there could make the callback late.
+#[derive(Debug, Default)]
+pub struct BridgeStats {
+    /// Input buffers that didn't all fit
+    pub overflows: AtomicUsize,
+    /// Output blocks that found too little input queued
+    pub underruns: AtomicUsize,
+}
+
+/// Writing end of a bridge, fed from the input stream's callback
+pub struct BridgeInput {
+    producer: Producer<f32>,
+    fill: Arc<AtomicUsize>,
+    channels: usize,
+    capacity: usize,
+    stats: Arc<BridgeStats>,
+}
+
+/// Reading end of a bridge, drained a block at a time from the output stream's callback
+pub struct BridgeOutput {
+    consumer: Consumer<f32>,
+    fill: Arc<AtomicUsize>,
+    channels: usize,
+    target: usize,
+    // How far the fill can wander before the output side steps in
+    tolerance: usize,
+    primed: bool,
+    // Scratch space for stretching, kept so the audio thread doesn't allocate
+    taken: Vec<f32>,
+    stats: Arc<BridgeStats>,
+}
+
+/// Carries input from one stream to another when they run on separate devices. The two
+/// clocks never quite agree, so the amount queued slowly wanders; when it strays too far
+/// from the target the output side reads a frame more or less than it needs and stretches
+/// that to fit, which keeps the streams together without ever running dry or overflowing.
+/// The two sides can have callbacks of different sizes.
+pub fn drift_bridge(channels: usize, in_frames: usize, out_frames: usize) -> (BridgeInput, BridgeOutput) {
+    let target = bridge_target(::std::cmp::max(in_frames, out_frames));
+    let capacity = 16 * target;
+    let (producer, consumer) = bounded_spsc_queue::make::<f32>(capacity * channels);
+    let fill = Arc::new(AtomicUsize::new(0));
+    let stats = Arc::new(BridgeStats::default());
+    (BridgeInput { producer: producer, fill: fill.clone(), channels: channels, capacity: capacity, stats: stats.clone() },
+     BridgeOutput { consumer: consumer, fill: fill, channels: channels, target: target, tolerance: target / 2,
+                    primed: false, taken: Vec::with_capacity((out_frames + 1) * channels), stats: stats })
+}
+
+impl BridgeInput {
+    /// Queues interleaved input. Whole frames that don't fit are dropped.
+    pub fn push(&self, buffer: &[f32]) {
+        for frame in buffer.chunks(self.channels) {
+            if self.fill.load(Ordering::SeqCst) >= self.capacity {
+                self.stats.overflows.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+            for s in frame.iter() {
+                self.producer.push(*s);
+            }
+            self.fill.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl BridgeOutput {
+    /// Frames waiting to be read
+    pub fn queued(&self) -> usize {
+        self.fill.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> Arc<BridgeStats> {
+        self.stats.clone()
+    }
+
+    /// Fills `block` with interleaved input, `block.len() / channels` frames of it. Gives
+    /// silence until enough has been queued to start.
+    pub fn pull(&mut self, block: &mut [f32]) {
+        let frames = block.len() / self.channels;
+        let fill = self.fill.load(Ordering::SeqCst);
+
+        if !self.primed {
+            if fill < self.target {
+                for s in block.iter_mut() { *s = 0.; }
+                return;
+            }
+            self.primed = true;
+        }
+
+        // A block of less than two frames has nothing to stretch over
+        let take = if frames < 2 {
+            frames
+        } else if fill > self.target + self.tolerance {
+            frames + 1
+        } else if fill < self.target - self.tolerance {
+            frames - 1
+        } else {
+            frames
+        };
+
+        if take > fill {
+            // Input has stopped or fallen badly behind; start over once it catches up
+            self.stats.underruns.fetch_add(1, Ordering::SeqCst);
+            self.primed = false;
+            for s in block.iter_mut() { *s = 0.; }
+            return;
+        }
+
+        if take == frames {
+            for s in block.iter_mut() {
+                *s = self.consumer.pop();
+            }
+            self.fill.fetch_sub(take, Ordering::SeqCst);
+            return;
+        }
+
+        // Read `take` frames and stretch them linearly over the block
+        self.taken.clear();
+        for _ in 0..take * self.channels {
+            self.taken.push(self.consumer.pop());
+        }
+        self.fill.fetch_sub(take, Ordering::SeqCst);
+        let taken = &self.taken;
+        let step = (take - 1) as f32 / (frames - 1).max(1) as f32;
+        for (i, frame) in block.chunks_mut(self.channels).enumerate() {
+            let position = i as f32 * step;
+            let (index, fraction) = (position as usize, position.fract());
+            let next = (index + 1).min(take - 1);
+            for (c, s) in frame.iter_mut().enumerate() {
+                let a = taken[index * self.channels + c];
+                let b = taken[next * self.channels + c];
+                *s = a + (b - a) * fraction;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a bridge between two clocks for `seconds`, calling back whichever is due next, and
+    // gives the least and most queued when the output read from it
+    fn run(in_rate: f64, out_rate: f64, in_frames: usize, out_frames: usize, seconds: f64) -> (usize, usize, Arc<BridgeStats>) {
+        let (input, mut output) = drift_bridge(2, in_frames, out_frames);
+        let in_buffer = vec![0.5; in_frames * 2];
+        let mut out_block = vec![0.; out_frames * 2];
+        let (mut in_time, mut out_time) = (0., 0.);
+        let (mut least, mut most) = (::std::usize::MAX, 0);
+        while in_time < seconds || out_time < seconds {
+            if in_time <= out_time {
+                input.push(&in_buffer);
+                in_time += in_frames as f64 / in_rate;
+            } else {
+                let queued = output.queued();
+                output.pull(&mut out_block);
+                if output.primed {
+                    least = least.min(queued);
+                    most = most.max(queued);
+                }
+                out_time += out_frames as f64 / out_rate;
+            }
+        }
+        (least, most, output.stats())
+    }
+
+    fn assert_steady(in_rate: f64, out_rate: f64, in_frames: usize, out_frames: usize) {
+        let (least, most, stats) = run(in_rate, out_rate, in_frames, out_frames, 20.);
+        let target = bridge_target(in_frames.max(out_frames));
+        let slack = target / 2 + in_frames.max(out_frames);
+        assert!(least + slack >= target, "fill fell to {}", least);
+        assert!(most <= target + slack, "fill rose to {}", most);
+        assert_eq!(stats.underruns.load(Ordering::SeqCst), 0);
+        assert_eq!(stats.overflows.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn keeps_up_with_a_fast_input_clock() {
+        assert_steady(48048., 48000., 64, 64);
+        assert_steady(48048., 48000., 64, 256);
+    }
+
+    #[test]
+    fn keeps_up_with_a_slow_input_clock() {
+        assert_steady(47952., 48000., 64, 64);
+        assert_steady(47952., 48000., 256, 64);
+    }
+
+    #[test]
+    fn gives_silence_until_primed() {
+        let (input, mut output) = drift_bridge(1, 64, 64);
+        input.push(&[1.; 64]);
+        let mut block = [1.; 64];
+        output.pull(&mut block);
+        assert!(block.iter().all(|s| *s == 0.));
+        assert_eq!(output.queued(), 64);
+    }
+
+    #[test]
+    fn reads_tiny_blocks_below_the_target() {
+        let (input, mut output) = drift_bridge(1, 64, 64);
+        let target = bridge_target(64);
+        input.push(&vec![1.; target]);
+        // Down to below the tolerance, where a bigger block would be stretched from fewer frames
+        output.pull(&mut vec![0.; target / 2 + 1]);
+        output.pull(&mut []);
+        let mut one = [0.];
+        output.pull(&mut one);
+        assert_eq!(one, [1.]);
+        assert_eq!(output.queued(), target / 2 - 2);
+    }
+
+    #[test]
+    fn counts_input_that_does_not_fit() {
+        let (input, output) = drift_bridge(1, 64, 64);
+        input.push(&vec![0.; 16 * bridge_target(64) + 1]);
+        assert_eq!(output.stats().overflows.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,132 @@
+use find_folder;
+use toml;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+pub const CONFIG_FILE: &'static str = "config.toml";
+
+/// Settings read from `assets/config.toml`. Every section is optional.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Action name to key name, see `Keymap::from_config`
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>,
+    #[serde(default)]
+    pub audio: AudioConfig,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    #[serde(default)]
+    pub segmentation: SegmentationConfig,
+    #[serde(default)]
+    pub matching: MatchingConfig,
+    /// Targets that can be switched between, the first reconstructed to start with. Left out
+    /// when saving if there are none, since toml can't write an empty array after the tables.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<TargetConfig>,
+    pub osc: Option<OscConfig>,
+    pub midi: Option<MidiConfig>,
+}
+
+/// `[audio]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioConfig {
+    /// Rate to open devices at. Target and source files are resampled to match.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// Frames handed to the audio callback at a time
+    #[serde(default = "default_frames_per_buffer")]
+    pub frames_per_buffer: u32,
+    /// Suggested device latency in seconds. Left out, each device's default low latency is used.
+    pub latency: Option<f64>,
+    /// Frames per buffer for the output when it has to run as a stream of its own, the same as
+    /// the input if left out
+    pub output_frames_per_buffer: Option<u32>,
+    /// Suggested output latency when it runs as a stream of its own, `latency` if left out
+    pub output_latency: Option<f64>,
+    /// Blocks of input that can queue up for the dictionary handler before any are dropped
+    #[serde(default = "default_queue_depth")]
+    pub queue_depth: usize,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            sample_rate: default_sample_rate(),
+            frames_per_buffer: default_frames_per_buffer(),
+            latency: None,
+            output_frames_per_buffer: None,
+            output_latency: None,
+            queue_depth: default_queue_depth(),
+        }
+    }
+}
+
+fn default_sample_rate() -> f64 {
+    44100.
+}
+
+fn default_frames_per_buffer() -> u32 {
+    BLOCK_SIZE as u32
+}
+
+fn default_queue_depth() -> usize {
+    65536
+}
+
+impl Config {
+    pub fn from_path<T>(path: &Path) -> Result<Config, Error<T>> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)));
+        let config = try!(toml::from_str(&contents));
+        Ok(config)
+    }
+
+    /// Loads the config from the assets folder, falling back on the defaults if there isn't one
+    pub fn load<T>() -> Result<Config, Error<T>> {
+        match default_path() {
+            Some(ref path) if path.exists() => Config::from_path(path),
+            _ => Ok(Config::default()),
+        }
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    find_folder::Search::KidsThenParents(3, 5).for_folder("assets").ok()
+        .map(|assets| assets.join(CONFIG_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whatever the config serializes to has to read back as the same config
+    fn round_trip(config: &Config) {
+        let contents = toml::to_string(config).unwrap();
+        let loaded: Config = toml::from_str(&contents).unwrap();
+        assert_eq!(toml::to_string(&loaded).unwrap(), contents);
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        round_trip(&Config::default());
+    }
+
+    #[test]
+    fn shipped_config_round_trips() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets").join(CONFIG_FILE);
+        let config = Config::from_path::<()>(&path).unwrap();
+        round_trip(&config);
+    }
+
+    #[test]
+    fn targets_round_trip() {
+        let mut config = Config::default();
+        config.targets.push(TargetConfig::new(PathBuf::from("inventing.wav")));
+        round_trip(&config);
+    }
+}
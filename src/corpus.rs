@@ -0,0 +1,190 @@
+use bincode::{self, Bounded, Infinite};
+use soundsym::*;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::*;
+
+// Start of every corpus file, followed by the format version and then the corpus itself
+const CORPUS_MAGIC: &'static [u8; 8] = b"RCORPUS\0";
+pub const CORPUS_VERSION: u8 = 1;
+
+/// One dictionary entry, with enough about where it came from to find it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorpusSegment {
+    /// File the audio was streamed from, or `None` for live input
+    pub source: Option<String>,
+    /// Start and end in seconds from the start of the capture
+    pub start: f64,
+    pub end: f64,
+    pub label: Option<String>,
+    pub samples: Vec<f64>,
+    /// `NCOEFFS` per analysis frame, as `Sound::mfccs` gives them. Empty if they have to be
+    /// worked out again, e.g. after resampling.
+    pub mfccs: Vec<f64>,
+}
+
+/// A `SoundDictionary` saved to disk, so captures can outlive the session that made them and
+/// be used again as the source of a reconstruction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Corpus {
+    pub sample_rate: f64,
+    pub segments: Vec<CorpusSegment>,
+}
+
+impl Corpus {
+    /// Gathers the dictionary's sounds along with the segments they were cut from and the
+    /// source of each
+    pub fn from_dictionary(dict: &SoundDictionary, splits: &[Segment], sources: &[Option<String>], sample_rate: f64) -> Corpus {
+        let segments = dict.sounds.iter().zip(splits.iter()).zip(sources.iter())
+            .map(|((sound, &(start, end)), source)| CorpusSegment {
+                source: source.clone(),
+                start: seconds(start),
+                end: seconds(end),
+                label: None,
+                samples: sound.samples().to_vec(),
+                mfccs: sound.mfccs().clone(),
+            })
+            .collect();
+        Corpus { sample_rate: sample_rate, segments: segments }
+    }
+
+    /// Labels every segment that doesn't have a label yet
+    pub fn label(&mut self, label: &str) {
+        for segment in self.segments.iter_mut().filter(|s| s.label.is_none()) {
+            segment.label = Some(label.to_string());
+        }
+    }
+
+    /// Converts the audio to another rate. The MFCCs no longer apply, so they're dropped.
+    pub fn resample(&mut self, sample_rate: f64) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        for segment in self.segments.iter_mut() {
+            segment.samples = resample(&segment.samples[..], self.sample_rate, sample_rate);
+            segment.mfccs.clear();
+        }
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn save<T>(&self, path: &Path) -> Result<(), Error<T>> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+        try!(writer.write_all(CORPUS_MAGIC));
+        try!(writer.write_all(&[CORPUS_VERSION]));
+        try!(bincode::serialize_into(&mut writer, self, Infinite)
+             .map_err(|e| Error::String(format!("cannot write corpus: {}", e))));
+        try!(writer.flush());
+        Ok(())
+    }
+
+    pub fn load<T>(path: &Path) -> Result<Corpus, Error<T>> {
+        let file = try!(File::open(path));
+        let size = try!(file.metadata()).len();
+        let mut reader = BufReader::new(file);
+        let mut header = [0u8; 9];
+        try!(reader.read_exact(&mut header));
+        if &header[..8] != CORPUS_MAGIC {
+            return Err(Error::String(format!("{} is not a corpus", path.display())));
+        }
+        if header[8] != CORPUS_VERSION {
+            return Err(Error::String(format!("{} is corpus version {}, expected {}", path.display(), header[8], CORPUS_VERSION)));
+        }
+        // Nothing in the corpus can take up more than the file does, so a corrupt length can't
+        // ask for more memory than that
+        bincode::deserialize_from(&mut reader, Bounded(size))
+            .map_err(|e| Error::String(format!("cannot read corpus: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn corpus() -> Corpus {
+        Corpus {
+            sample_rate: 44100.,
+            segments: vec![
+                CorpusSegment {
+                    source: Some("take.wav".to_string()),
+                    start: 0.,
+                    end: 0.5,
+                    label: Some("kick".to_string()),
+                    samples: vec![0.1, -0.2, 0.3],
+                    mfccs: vec![1., 2.],
+                },
+                CorpusSegment {
+                    source: None,
+                    start: 0.5,
+                    end: 1.,
+                    label: None,
+                    samples: vec![0.4],
+                    mfccs: Vec::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = ScratchDir::new("corpus-round-trip");
+        let path = dir.join("round-trip.corpus");
+        corpus().save::<()>(&path).unwrap();
+        let loaded = Corpus::load::<()>(&path).unwrap();
+        let original = corpus();
+        assert_eq!(loaded.sample_rate, original.sample_rate);
+        assert_eq!(loaded.segments.len(), original.segments.len());
+        for (a, b) in loaded.segments.iter().zip(original.segments.iter()) {
+            assert_eq!(a.source, b.source);
+            assert_eq!((a.start, a.end), (b.start, b.end));
+            assert_eq!(a.label, b.label);
+            assert_eq!(a.samples, b.samples);
+            assert_eq!(a.mfccs, b.mfccs);
+        }
+    }
+
+    fn write(dir: &ScratchDir, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn rejects_other_files_and_versions() {
+        let dir = ScratchDir::new("corpus-headers");
+        assert!(Corpus::load::<()>(&write(&dir, "not-a.corpus", b"RIFF\0\0\0\0\x01")).is_err());
+        let mut header = CORPUS_MAGIC.to_vec();
+        header.push(CORPUS_VERSION + 1);
+        assert!(Corpus::load::<()>(&write(&dir, "future.corpus", &header)).is_err());
+    }
+
+    #[test]
+    fn rejects_lengths_longer_than_the_file() {
+        let dir = ScratchDir::new("corpus-lengths");
+        let mut contents = CORPUS_MAGIC.to_vec();
+        contents.push(CORPUS_VERSION);
+        // Sample rate, then a segment count far past the end of the file
+        contents.extend_from_slice(&[0; 8]);
+        contents.extend_from_slice(&[0xff; 8]);
+        assert!(Corpus::load::<()>(&write(&dir, "corrupt.corpus", &contents)).is_err());
+    }
+
+    #[test]
+    fn labels_only_unlabelled_segments() {
+        let mut corpus = corpus();
+        corpus.label("snare");
+        assert_eq!(corpus.segments[0].label, Some("kick".to_string()));
+        assert_eq!(corpus.segments[1].label, Some("snare".to_string()));
+    }
+
+    #[test]
+    fn resampling_drops_the_mfccs() {
+        let mut corpus = corpus();
+        corpus.resample(22050.);
+        assert_eq!(corpus.sample_rate, 22050.);
+        assert!(corpus.segments.iter().all(|s| s.mfccs.is_empty()));
+    }
+}
@@ -0,0 +1,49 @@
+// Conversions between durations, seconds and sample positions. Durations are built from
+// whole nanoseconds, so short ones like a block of audio don't get rounded to the millisecond.
+
+use std::time::Duration;
+
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Negative lengths of time come out as nothing
+pub fn seconds_duration(seconds: f64) -> Duration {
+    let nanos = (seconds.max(0.) * 1e9).round() as u64;
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// The sample nearest to `duration` into a sound
+pub fn duration_sample(duration: Duration, sample_rate: f64) -> usize {
+    (seconds(duration) * sample_rate).round() as usize
+}
+
+pub fn sample_duration(sample: usize, sample_rate: f64) -> Duration {
+    seconds_duration(sample as f64 / sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_sub_millisecond_precision() {
+        let block = seconds_duration(64. / 44100.);
+        assert_eq!(block.as_secs(), 0);
+        assert_eq!(block.subsec_nanos(), 1_451_247);
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        for &sample in [0, 1, 441, 44100, 1234567].iter() {
+            assert_eq!(duration_sample(sample_duration(sample, 44100.), 44100.), sample);
+        }
+    }
+
+    #[test]
+    fn whole_seconds_carry() {
+        let duration = seconds_duration(2.5);
+        assert_eq!(duration, Duration::new(2, 500_000_000));
+        assert_eq!(seconds(duration), 2.5);
+    }
+}
@@ -1,12 +1,15 @@
 use std;
 use conrod;
 use portaudio;
+use toml;
 
 #[derive(Debug)]
 pub enum Error<T> {
     PortAudio(portaudio::Error),
     Font(conrod::text::font::Error),
     SendError(std::sync::mpsc::SendError<T>),
+    Io(std::io::Error),
+    Config(toml::de::Error),
     String(String)
 }
 
@@ -34,12 +37,26 @@ impl<T> From<std::sync::mpsc::SendError<T>> for Error<T> {
     }
 }
 
+impl<T> From<std::io::Error> for Error<T> {
+    fn from(err: std::io::Error) -> Error<T> {
+        Error::Io(err)
+    }
+}
+
+impl<T> From<toml::de::Error> for Error<T> {
+    fn from(err: toml::de::Error) -> Error<T> {
+        Error::Config(err)
+    }
+}
+
 impl<T> std::fmt::Display for Error<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             Error::PortAudio(ref err) => write!(f, "PortAudio error: {}", err),
             Error::Font(ref err) => write!(f, "Font error: {}", err),
             Error::SendError(ref err) => write!(f, "Send error: {}", err),
+            Error::Io(ref err) => write!(f, "IO error: {}", err),
+            Error::Config(ref err) => write!(f, "Config error: {}", err),
             Error::String(ref err) => write!(f, "String error: {}", err)
         }
     }
@@ -51,6 +68,8 @@ impl<T: std::marker::Send + std::fmt::Debug> std::error::Error for Error<T> {
             Error::PortAudio(ref err) => err.description(),
             Error::Font(ref err) => err.description(),
             Error::SendError(ref err) =>  err.description(),
+            Error::Io(ref err) => err.description(),
+            Error::Config(ref err) => err.description(),
             Error::String(ref err) => err
         }
     }
@@ -60,6 +79,8 @@ impl<T: std::marker::Send + std::fmt::Debug> std::error::Error for Error<T> {
             Error::PortAudio(ref err) => Some(err),
             Error::Font(ref err) => Some(err),
             Error::SendError(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            Error::Config(ref err) => Some(err),
             Error::String(_) => None
         }
     }
@@ -1,14 +1,67 @@
 extern crate bounded_spsc_queue;
 extern crate portaudio;
 
-use portaudio::{DeviceIndex, DeviceInfo};
+use portaudio::DeviceInfo;
+
+use std::path::PathBuf;
+
+use backend::{DeviceEntry, StreamInfo};
+use features::FeatureMatrix;
+use gate::GateReport;
+use handlers::BLOCK_SIZE;
+use matcher::MatcherKind;
+use selection::SelectionKind;
+use library::{DictionaryInfo, Provenance};
+use segment::SegmenterKind;
+use target::TargetInfo;
 
 pub enum DictionaryHandlerEvent {
     Refresh,
     Play,
+    /// Stop playback, dropping whatever hasn't been played yet
+    Stop,
+    /// Write the latest reconstruction to a WAV file, with where each segment came from
+    /// alongside it
+    Export(PathBuf),
+    /// Save the latest capture dictionary as a corpus, labelling its segments if a label is given
+    SaveCorpus(PathBuf, Option<String>),
+    /// Add a saved corpus to the dictionaries the target is reconstructed from, named after
+    /// its file, and reconstruct
+    LoadCorpus(PathBuf),
+    /// Switch a dictionary in or out of the reconstruction
+    SetDictionaryEnabled(String, bool),
+    /// Weight a dictionary's segments against the others; above 1 favours them
+    SetDictionaryWeight(String, f64),
+    RemoveDictionary(String),
+    /// Change how segments are matched, and reconstruct again
+    SetMatcher(MatcherKind),
+    /// Change how the segments standing in for the target are chosen, and reconstruct again
+    SetSelection(SelectionKind),
+    /// Reconstruct the target at this position in the playlist, as soon as it's loaded
+    SetTarget(usize),
+    /// Where the next input comes from, for the corpus: a file, or live input if `None`
+    SourceFile(Option<PathBuf>),
+    /// Throw away everything captured so far
+    Clear,
+    /// Restart playback whenever it runs out
+    SetLoop(bool),
+    /// Write incoming input to disk
+    SetRecording(bool),
     SetThreshold(usize),
     SetDepth(usize),
-    InputBuffer(Option<bounded_spsc_queue::Consumer<[f32; 64]>>),
+    /// Change how the target or captured input is split up. The target is split again
+    /// straight away.
+    SetSegmenter(FeatureSource, SegmenterKind),
+    /// Grain length in seconds for the grain segmenter
+    SetGrainLength(f64),
+    /// Level in dBFS captured segments have to reach to be kept
+    SetGateLevel(f64),
+    /// The device rate changed; the target and anything captured are converted to it
+    SampleRate(f64),
+    InputBuffer(Option<bounded_spsc_queue::Consumer<[f32; BLOCK_SIZE]>>),
+    /// Input carries on from a new stream, e.g. after a device change. Whatever the old one
+    /// left in its queue is still captured, and recording continues in the same file.
+    SwapInput(bounded_spsc_queue::Consumer<[f32; BLOCK_SIZE]>),
     Quit
 }
 
@@ -17,6 +70,13 @@ pub enum AudioHandlerEvent {
     Setting(DeviceSetting),
     Start,
     Stop,
+    /// Set one of the mix levels, see `ChannelMix`
+    SetLevel(Level, f32),
+    /// Feed the dictionary from a WAV file at the given multiple of realtime (0 for as fast as
+    /// possible) instead of the live input
+    LoadSource(PathBuf, f64),
+    /// Look for devices plugged in or removed since startup
+    Rescan,
     Quit
 }
 
@@ -24,11 +84,96 @@ pub enum AudioHandlerEvent {
 pub enum DeviceSetting {
     SetInDevice(u32),
     SetOutDevice(u32),
+    SetSampleRate(f64),
+    /// Input channels to capture, counting from 0, averaged if there's more than one
+    SetInputChannels(Vec<usize>),
+    /// Position of the output between the left and right channels, from -1 to 1
+    SetPan(f32),
+    /// Frames the device hands the callback at a time
+    SetFramesPerBuffer(u32),
+    /// Suggested device latency in seconds, or `None` for the device's default
+    SetLatency(Option<f64>),
+    /// Blocks of input that can wait for the dictionary handler before being dropped
+    SetQueueDepth(usize),
+}
+
+/// Gain controls in the audio callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    InputGain,
+    Monitor,
+    Reconstruction,
+}
+
+pub const LEVELS: [Level; 3] = [Level::InputGain, Level::Monitor, Level::Reconstruction];
+
+impl Level {
+    /// Name used in commands
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::InputGain => "input",
+            Level::Monitor => "monitor",
+            Level::Reconstruction => "reconstruction",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        LEVELS.iter().find(|l| l.name() == name).cloned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureSource {
+    Target,
+    Capture,
 }
 
 pub enum GuiHandlerEvent {
     InDevice(usize),
     OutDevice(usize),
-    Devices(Vec<(DeviceIndex, String)>),
+    Devices(Vec<DeviceEntry>),
+    SampleRate(f64),
+    /// The target couldn't be converted to `rate`, so the device should go back to `previous`
+    SampleRateRejected { rate: f64, previous: f64 },
+    /// Number of channels the input device can be captured from
+    InputChannels(usize),
+    /// The stream was opened, reopened or closed
+    Stream(Option<StreamInfo>),
+    /// Buffer settings in effect, which may differ from what was asked for
+    Buffer { frames_per_buffer: u32, latency: Option<f64>, queue_depth: usize },
+    /// Measured time from input to output in seconds, if the backend can tell
+    RoundTrip(Option<f64>),
+    Features(FeatureSource, FeatureMatrix),
+    /// The segmenter in use for the target or captured input
+    Segmenter(FeatureSource, SegmenterKind),
+    /// Grain length in seconds
+    GrainLength(f64),
+    /// Gate level in dBFS
+    GateLevel(f64),
+    /// What the gate let through on the last reconstruction
+    Gated(GateReport),
+    /// The distance measure segments are matched by
+    Matcher(MatcherKind),
+    /// How the segments standing in for the target are chosen
+    Selection(SelectionKind),
+    /// The dictionaries in the library and how much of the last reconstruction each supplied
+    Dictionaries(Vec<DictionaryInfo>),
+    /// Where each segment of the last reconstruction came from
+    Provenance(Vec<Provenance>),
+    /// The playlist, and which of its targets is being reconstructed
+    Targets { targets: Vec<TargetInfo>, current: usize },
+}
+
+/// Progress reports for remote control surfaces
+#[derive(Debug, Clone)]
+pub enum StatusEvent {
+    /// Number of segments in the target and in the dictionaries switched on
+    Segments { target: usize, capture: usize },
+    Playing(bool),
+    Dsp(bool),
+    /// Captured segments the gate kept and dropped on the last reconstruction
+    Gated { kept: usize, dropped: usize },
+    /// Position in the playlist of the target being reconstructed
+    Target(usize),
 }
 
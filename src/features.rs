@@ -0,0 +1,154 @@
+use soundsym::NCOEFFS;
+
+/// Row-major matrix of MFCC frames, one row per analysis frame and one column per coefficient.
+#[derive(Debug, Clone)]
+pub struct FeatureMatrix {
+    pub rows: usize,
+    pub cols: usize,
+    pub data: Vec<f64>,
+}
+
+impl FeatureMatrix {
+    /// Wraps the flat output of `Sound::mfccs()`
+    pub fn from_mfccs(mfccs: &[f64]) -> FeatureMatrix {
+        let rows = mfccs.len() / NCOEFFS;
+        FeatureMatrix {
+            rows: rows,
+            cols: NCOEFFS,
+            data: mfccs[..(rows * NCOEFFS)].to_vec(),
+        }
+    }
+
+    pub fn frame(&self, row: usize) -> &[f64] {
+        &self.data[(row * self.cols)..((row + 1) * self.cols)]
+    }
+
+    /// Renders the matrix as an RGBA heatmap with time running left to right and the first
+    /// coefficient at the bottom. Frames are averaged down so the image is at most `max_width`
+    /// pixels wide. Each coefficient is normalized on its own, otherwise the energy term in the
+    /// first coefficient washes out everything else.
+    ///
+    /// Returns the width, height and pixel data.
+    pub fn to_rgba(&self, max_width: usize) -> (u32, u32, Vec<u8>) {
+        let width = ::std::cmp::max(1, ::std::cmp::min(self.rows, max_width));
+        let height = ::std::cmp::max(1, self.cols);
+
+        let mut columns = vec![0f64; width * self.cols];
+        for x in 0..width {
+            let start = x * self.rows / width;
+            let end = ::std::cmp::max(start + 1, (x + 1) * self.rows / width);
+            let end = ::std::cmp::min(end, self.rows);
+            for row in start..end {
+                for (c, v) in self.frame(row).iter().enumerate() {
+                    columns[x * self.cols + c] += *v / (end - start) as f64;
+                }
+            }
+        }
+
+        let mut ranges = vec![(::std::f64::MAX, ::std::f64::MIN); self.cols];
+        for x in 0..width {
+            for c in 0..self.cols {
+                let v = columns[x * self.cols + c];
+                ranges[c].0 = ranges[c].0.min(v);
+                ranges[c].1 = ranges[c].1.max(v);
+            }
+        }
+
+        let mut pixels = vec![0u8; width * height * 4];
+        for x in 0..width {
+            for c in 0..self.cols {
+                let (min, max) = ranges[c];
+                let v = columns[x * self.cols + c];
+                let norm = if max > min { (v - min) / (max - min) } else { 0. };
+                let y = height - 1 - c;
+                let offset = (y * width + x) * 4;
+                pixels[offset..(offset + 4)].copy_from_slice(&heat(norm));
+            }
+        }
+
+        (width as u32, height as u32, pixels)
+    }
+}
+
+/// Maps 0..1 onto a dark blue -> red -> yellow ramp
+fn heat(v: f64) -> [u8; 4] {
+    let v = v.max(0.).min(1.);
+    let r = (v * 2.).min(1.);
+    let g = (v * 2. - 1.).max(0.);
+    let b = 0.4 * (1. - v);
+    [(r * 255.) as u8, (g * 255.) as u8, (b * 255.) as u8, 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(rgba: &(u32, u32, Vec<u8>), x: usize, coefficient: usize) -> [u8; 4] {
+        let (width, height) = (rgba.0 as usize, rgba.1 as usize);
+        let offset = ((height - 1 - coefficient) * width + x) * 4;
+        [rgba.2[offset], rgba.2[offset + 1], rgba.2[offset + 2], rgba.2[offset + 3]]
+    }
+
+    #[test]
+    fn drops_a_partial_frame() {
+        let mut mfccs = vec![1.; 2 * NCOEFFS];
+        mfccs.push(2.);
+        let matrix = FeatureMatrix::from_mfccs(&mfccs);
+        assert_eq!((matrix.rows, matrix.cols), (2, NCOEFFS));
+        assert_eq!(matrix.data.len(), 2 * NCOEFFS);
+        assert_eq!(matrix.frame(1), &vec![1.; NCOEFFS][..]);
+    }
+
+    // Four frames: the first coefficient rises, the second falls and the third rises far more
+    // steeply than the first
+    fn ramps() -> FeatureMatrix {
+        let mut mfccs = vec![0.; 4 * NCOEFFS];
+        for row in 0..4 {
+            mfccs[row * NCOEFFS] = row as f64;
+            mfccs[row * NCOEFFS + 1] = -(row as f64);
+            mfccs[row * NCOEFFS + 2] = 1000. * row as f64;
+        }
+        FeatureMatrix::from_mfccs(&mfccs)
+    }
+
+    #[test]
+    fn averages_frames_down_to_the_width() {
+        let rgba = ramps().to_rgba(2);
+        assert_eq!((rgba.0, rgba.1), (2, NCOEFFS as u32));
+        assert_eq!(rgba.2.len(), 2 * NCOEFFS * 4);
+        assert_eq!(pixel(&rgba, 0, 0), heat(0.));
+        assert_eq!(pixel(&rgba, 1, 0), heat(1.));
+
+        let full = ramps().to_rgba(10);
+        assert_eq!(full.0, 4);
+        assert_eq!(pixel(&full, 1, 0), heat(1. / 3.));
+    }
+
+    #[test]
+    fn normalizes_each_coefficient() {
+        let rgba = ramps().to_rgba(4);
+        for x in 0..4 {
+            assert_eq!(pixel(&rgba, x, 2), pixel(&rgba, x, 0));
+            assert_eq!(pixel(&rgba, x, 1), pixel(&rgba, 3 - x, 0));
+        }
+        // A coefficient that never changes gets the bottom of the ramp
+        assert_eq!(pixel(&rgba, 3, 3), heat(0.));
+    }
+
+    #[test]
+    fn no_frames_is_a_blank_column() {
+        let rgba = FeatureMatrix::from_mfccs(&[]).to_rgba(100);
+        assert_eq!((rgba.0, rgba.1), (1, NCOEFFS as u32));
+        for c in 0..NCOEFFS {
+            assert_eq!(pixel(&rgba, 0, c), heat(0.));
+        }
+    }
+
+    #[test]
+    fn heat_clamps() {
+        assert_eq!(heat(-1.), heat(0.));
+        assert_eq!(heat(2.), heat(1.));
+        assert_eq!(heat(1.), [255, 255, 0, 255]);
+        assert_eq!(heat(0.), [0, 0, 102, 255]);
+    }
+}
@@ -0,0 +1,183 @@
+use super::*;
+
+// Pole of the DC blocking filter; closer to 1 leaves more of the low end alone
+const DC_POLE: f64 = 0.995;
+// Frame length in seconds the gate measures the level over
+const GATE_FRAME: f64 = 0.01;
+
+/// `[segmentation.gate]` section of the config file. Anything left out takes its default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GateConfig {
+    /// Level in dBFS a segment has to reach somewhere to be kept
+    pub level: f64,
+    /// Cut quiet stretches off the start and end of the segments that are kept
+    pub trim: bool,
+    /// Shortest segment in seconds left after trimming; anything shorter is dropped
+    pub min_length: f64,
+}
+
+impl Default for GateConfig {
+    fn default() -> GateConfig {
+        GateConfig {
+            level: -50.,
+            trim: true,
+            min_length: 0.02,
+        }
+    }
+}
+
+/// What the gate did to a set of segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GateReport {
+    pub kept: usize,
+    /// Kept, but shortened
+    pub trimmed: usize,
+    pub dropped: usize,
+}
+
+/// Takes out any DC offset with a one-pole high-pass filter, so an offset doesn't read as
+/// level to the gate or end up in the reconstruction. It keeps its state between calls, so
+/// input can be filtered a block at a time as it arrives.
+#[derive(Debug, Clone, Default)]
+pub struct DcBlocker {
+    previous_in: f64,
+    previous_out: f64,
+}
+
+impl DcBlocker {
+    pub fn new() -> DcBlocker {
+        DcBlocker::default()
+    }
+
+    /// Filters `samples` in place, carrying on from the last block
+    pub fn process(&mut self, samples: &mut [f64]) {
+        for x in samples.iter_mut() {
+            let y = *x - self.previous_in + DC_POLE * self.previous_out;
+            self.previous_in = *x;
+            self.previous_out = y;
+            *x = y;
+        }
+    }
+}
+
+/// Drops segments of captured input that never get above the gate level, which are mostly
+/// silence and room noise, and trims the quiet ends off the rest
+pub struct Gate {
+    config: GateConfig,
+    sample_rate: f64,
+}
+
+impl Gate {
+    pub fn new(config: GateConfig, sample_rate: f64) -> Gate {
+        Gate { config: config, sample_rate: sample_rate }
+    }
+
+    /// Filters segments of `samples`, which should already have been through a `DcBlocker`
+    pub fn apply(&self, samples: &[f64], segments: &[Segment]) -> (Vec<Segment>, GateReport) {
+        let frame = ((GATE_FRAME * self.sample_rate) as usize).max(1);
+        let min = (self.config.min_length * self.sample_rate) as usize;
+        let mut report = GateReport::default();
+        let mut kept = Vec::with_capacity(segments.len());
+
+        for &(start, end) in segments.iter() {
+            let start = duration_sample(start, self.sample_rate).min(samples.len());
+            let end = duration_sample(end, self.sample_rate).min(samples.len());
+
+            // Start of each frame loud enough to count
+            let frames = (end.saturating_sub(start) + frame - 1) / frame;
+            let loud: Vec<usize> = (0..frames).map(|n| start + n * frame)
+                .filter(|&f| rms_level(&samples[f..(f + frame).min(end)]) >= self.config.level)
+                .collect();
+
+            let (first, last) = match (loud.first(), loud.last()) {
+                (Some(&first), Some(&last)) => (first, (last + frame).min(end)),
+                _ => {
+                    report.dropped += 1;
+                    continue;
+                }
+            };
+            let (new_start, new_end) = if self.config.trim { (first, last) } else { (start, end) };
+            if new_end - new_start < min {
+                report.dropped += 1;
+                continue;
+            }
+            if (new_start, new_end) != (start, end) {
+                report.trimmed += 1;
+            }
+            report.kept += 1;
+            kept.push((sample_duration(new_start, self.sample_rate), sample_duration(new_end, self.sample_rate)));
+        }
+        (kept, report)
+    }
+}
+
+/// RMS level in dBFS
+pub fn rms_level(samples: &[f64]) -> f64 {
+    if samples.is_empty() {
+        return ::std::f64::NEG_INFINITY;
+    }
+    let rms = (samples.iter().map(|s| s * s).sum::<f64>() / samples.len() as f64).sqrt();
+    20. * rms.max(1e-10).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 1000.;
+
+    fn segment(start: usize, end: usize) -> Segment {
+        (sample_duration(start, RATE), sample_duration(end, RATE))
+    }
+
+    // Silent but for a burst from 100 to 200 and a single 10ms frame from 300
+    fn samples() -> Vec<f64> {
+        (0..400).map(|n| if (n >= 100 && n < 200) || (n >= 300 && n < 310) { 0.5 } else { 0. }).collect()
+    }
+
+    #[test]
+    fn trims_quiet_ends_and_drops_silence() {
+        let gate = Gate::new(GateConfig::default(), RATE);
+        let (kept, report) = gate.apply(&samples(), &[segment(0, 250), segment(200, 300), segment(100, 200)]);
+        assert_eq!(kept, vec![segment(100, 200), segment(100, 200)]);
+        assert_eq!(report, GateReport { kept: 2, trimmed: 1, dropped: 1 });
+    }
+
+    #[test]
+    fn drops_what_trimming_leaves_too_short() {
+        let gate = Gate::new(GateConfig::default(), RATE);
+        let (kept, report) = gate.apply(&samples(), &[segment(250, 400)]);
+        assert!(kept.is_empty());
+        assert_eq!(report.dropped, 1);
+    }
+
+    #[test]
+    fn keeps_whole_segments_without_trimming() {
+        let gate = Gate::new(GateConfig { trim: false, ..GateConfig::default() }, RATE);
+        let (kept, report) = gate.apply(&samples(), &[segment(0, 250), segment(250, 400)]);
+        assert_eq!(kept, vec![segment(0, 250), segment(250, 400)]);
+        assert_eq!(report, GateReport { kept: 2, trimmed: 0, dropped: 0 });
+    }
+
+    #[test]
+    fn removes_an_offset_block_by_block() {
+        let mut whole = vec![1.; 4000];
+        DcBlocker::new().process(&mut whole);
+        assert!(whole[3999].abs() < 1e-6);
+
+        let mut blocks = vec![1.; 4000];
+        let mut blocker = DcBlocker::new();
+        for block in blocks.chunks_mut(64) {
+            blocker.process(block);
+        }
+        assert_eq!(blocks, whole);
+    }
+
+    #[test]
+    fn measures_level_in_dbfs() {
+        assert!((rms_level(&[1., -1.]) - 0.).abs() < 1e-9);
+        assert!((rms_level(&[0.1; 10]) + 20.).abs() < 1e-9);
+        assert_eq!(rms_level(&[]), ::std::f64::NEG_INFINITY);
+    }
+}
@@ -5,59 +5,146 @@ extern crate bounded_spsc_queue;
 extern crate conrod;
 extern crate find_folder;
 extern crate piston_window;
+extern crate hound;
 
 use soundsym::*;
-use portaudio::{Continue, DuplexStreamCallbackArgs, DuplexStreamSettings, PortAudio, StreamParameters, DeviceIndex};
+use portaudio::DeviceIndex;
 use bounded_spsc_queue::{Producer, Consumer};
 use crossbeam::sync::SegQueue;
-use rusty_machine::prelude::*;
 
-use std::borrow::Cow;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::mem::transmute;
+use std::path::{Path, PathBuf};
 use std::{thread, time};
 
 use super::*;
 
 // Import relevant structs
-use piston_window::{PistonWindow, Window, AdvancedWindow, G2d, G2dTexture, Texture, TextureSettings, WindowSettings};
-use piston_window::texture::UpdateTexture;
+use piston_window::{PistonWindow, Window, AdvancedWindow, G2d, G2dTexture, Texture, TextureSettings, WindowSettings, ResizeEvent};
+use piston_window::texture::{CreateTexture, Format, UpdateTexture};
 use conrod::backend::piston::event::{convert, UpdateEvent};
 use conrod::{color, widget, Colorable, Positionable, Sizeable, Widget, Labelable};
 
-// Set window dimensions
+// Initial window dimensions; the layout follows the window when it's resized
 const WIDTH: u32 = 800;
-const HEIGHT: u32 = 600;
+const HEIGHT: u32 = 700;
 
-const BLOCK_SIZE: usize = 64;
-const DEFAULT_THRESHOLD: usize = 5;
-const DEFAULT_DEPTH: usize = 4;
+// Layout metrics
+const PANEL_HEIGHT: f64 = 70.;
+const PANEL_PAD: f64 = 10.;
+const MARGIN: f64 = 10.;
+const LABEL_HEIGHT: f64 = 24.;
+
+pub const BLOCK_SIZE: usize = 64;
+pub const DEFAULT_THRESHOLD: usize = 5;
+pub const DEFAULT_DEPTH: usize = 4;
+// Rates offered in the GUI
+const SAMPLE_RATES: [f64; 6] = [22050., 32000., 44100., 48000., 88200., 96000.];
+const BUFFER_SIZES: [u32; 7] = [32, 64, 128, 256, 512, 1024, 2048];
+// Suggested latencies in milliseconds, after the device default
+const LATENCIES: [f64; 7] = [1., 2., 5., 10., 20., 50., 100.];
+const QUEUE_DEPTHS: [usize; 5] = [256, 1024, 4096, 16384, 65536];
+// Dictionary weights the GUI offers, and the fewest dictionaries the library panel is laid
+// out for so one on its own isn't stretched across the window
+const MAX_DICTIONARY_WEIGHT: f64 = 4.;
+const MIN_DICTIONARY_COLUMNS: usize = 3;
+// Grain lengths in milliseconds
+const GRAIN_LENGTHS: [f64; 7] = [10., 20., 50., 100., 200., 500., 1000.];
+// Widest heatmap texture we'll upload, in analysis frames
+const FEATURE_VIEW_MAX_FRAMES: usize = 1024;
 
 widget_ids! {
     pub struct Ids { 
         canvas, 
+        transport_panel,
+        devices_panel,
+        parameters_panel,
+        mix_panel,
+        library_panel,
+        visualizations_panel,
         plot, 
         reconstruct_button, 
         play_button, 
         launch_audio_button,
+        corpus_box,
+        save_corpus_button,
+        load_corpus_button,
         stop_audio_button,
         in_devices_list,
         out_devices_list,
+        in_channels_list,
+        pan_slider,
+        level_sliders[],
+        gate_slider,
+        matcher_list,
+        selection_list,
+        dictionary_toggles[],
+        dictionary_weights[],
+        library_text,
+        sample_rate_list,
+        buffer_list,
+        latency_list,
+        queue_list,
+        round_trip_text,
         analyze_sound_button,
         threshold_box, 
+        threshold_label,
         depth_box,
+        depth_label,
+        midi_learn_list,
+        target_segmenter_list,
+        capture_segmenter_list,
+        grain_list,
+        target_list,
+        source_box,
+        speed_box,
+        load_source_button,
+        rescan_button,
         audio_device,
+        target_features,
+        target_features_label,
+        capture_features,
+        capture_features_label,
+        help_overlay,
+        help_text,
     }
 }
 
 struct ReconstructionApp {
     threshold_text: String,
     depth_text: String,
-    devices: Option<Vec<(DeviceIndex, String)>>,
+    source_text: String,
+    speed_text: String,
+    corpus_text: String,
+    devices: Option<Vec<DeviceEntry>>,
     in_device: Option<usize>,
     out_device: Option<usize>,
+    sample_rate: Option<f64>,
+    frames_per_buffer: Option<u32>,
+    latency: Option<f64>,
+    queue_depth: Option<usize>,
+    stream: Option<StreamInfo>,
+    round_trip: Option<f64>,
+    target_segmenter: Option<SegmenterKind>,
+    capture_segmenter: Option<SegmenterKind>,
+    grain_length: Option<f64>,
+    gate_level: f64,
+    gated: Option<GateReport>,
+    dictionaries: Vec<DictionaryInfo>,
+    matcher: Option<MatcherKind>,
+    selection: Option<SelectionKind>,
+    targets: Vec<TargetInfo>,
+    current_target: Option<usize>,
+    input_channels: usize,
+    /// Index into the channel list: 0 for all channels, otherwise the channel number
+    input_channel_choice: usize,
+    pan: f32,
+    levels: ChannelMix,
+    target_features: Option<conrod::image::Id>,
+    capture_features: Option<conrod::image::Id>,
+    show_help: bool,
     window: PistonWindow,
 }
 
@@ -73,20 +160,98 @@ impl ReconstructionApp {
         window.set_position([0, 0]);
 
         Ok(ReconstructionApp {
-            threshold_text: String::new(),
-            depth_text: String::new(),
+            threshold_text: DEFAULT_THRESHOLD.to_string(),
+            depth_text: DEFAULT_DEPTH.to_string(),
+            source_text: String::new(),
+            speed_text: "1".to_string(),
+            corpus_text: String::new(),
             devices: None,
             in_device: None,
             out_device: None,
+            sample_rate: None,
+            frames_per_buffer: None,
+            latency: None,
+            queue_depth: None,
+            stream: None,
+            round_trip: None,
+            target_segmenter: None,
+            capture_segmenter: None,
+            grain_length: None,
+            gate_level: GateConfig::default().level,
+            gated: None,
+            dictionaries: Vec::new(),
+            matcher: None,
+            selection: None,
+            targets: Vec::new(),
+            current_target: None,
+            input_channels: 1,
+            input_channel_choice: 1,
+            pan: 0.,
+            levels: ChannelMix::default(),
+            target_features: None,
+            capture_features: None,
+            show_help: false,
             window: window,
         })
     }
 }
 
-pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>, dictionary_commands_producer: mpsc::Sender<DictionaryHandlerEvent>, gui_recv: mpsc::Receiver<GuiHandlerEvent>) -> Result<(), Error<T>> {
+/// The title bar is the one place with room to spare for the stream's details
+fn window_title(stream: &Option<StreamInfo>, round_trip: Option<f64>) -> String {
+    match *stream {
+        Some(ref info) => {
+            let mut title = format!("Reconstruction - {} Hz, latency {:.1} ms in, {:.1} ms out",
+                                    info.sample_rate, info.input_latency * 1000., info.output_latency * 1000.);
+            if let Some(round_trip) = round_trip {
+                title.push_str(&format!(", {:.1} ms round trip", round_trip * 1000.));
+            }
+            title
+        }
+        None => "Reconstruction".to_string(),
+    }
+}
+
+/// Uploads a heatmap of the feature matrix as a texture
+fn feature_texture<F>(factory: &mut F, features: &FeatureMatrix) -> Option<G2dTexture> 
+    where G2dTexture: CreateTexture<F>
+{
+    let (width, height, pixels) = features.to_rgba(FEATURE_VIEW_MAX_FRAMES);
+    G2dTexture::create(factory, Format::Rgba8, &pixels[..], [width, height], &TextureSettings::new()).ok()
+}
+
+/// Allocates a glyph cache and the texture it's rendered into for a window of the given size
+fn text_caches<T>(window: &mut PistonWindow, width: u32, height: u32) -> Result<(conrod::text::GlyphCache, G2dTexture), Error<T>> {
+    const SCALE_TOLERANCE: f32 = 0.1;
+    const POSITION_TOLERANCE: f32 = 0.1;
+    let (width, height) = (::std::cmp::max(width, 1), ::std::cmp::max(height, 1));
+    let cache = conrod::text::GlyphCache::new(width, height, SCALE_TOLERANCE, POSITION_TOLERANCE);
+
+    let buffer_len = width as usize * height as usize;
+    let init = vec![128; buffer_len];
+    let settings = TextureSettings::new();
+    let factory = &mut window.factory;
+    let texture = try!(G2dTexture::from_memory_alpha(factory, &init, width, height, &settings)
+                       .map_err(|e| Error::String(format!("cannot allocate text texture: {:?}", e))));
+    Ok((cache, texture))
+}
+
+/// Width of each of `n` widgets laid out in a row across the panel
+fn column_width(ui: &conrod::UiCell, panel: widget::Id, n: usize) -> f64 {
+    let w = ui.kid_area_of(panel).map(|r| r.w()).unwrap_or(ui.win_w);
+    ((w - MARGIN * (n as f64 - 1.)) / n as f64).max(0.)
+}
+
+/// Height of each of `n` rows stacked in the panel
+fn row_height(ui: &conrod::UiCell, panel: widget::Id, n: usize) -> f64 {
+    let h = ui.kid_area_of(panel).map(|r| r.h()).unwrap_or(n as f64 * PANEL_HEIGHT - 2. * PANEL_PAD);
+    ((h - MARGIN * (n as f64 - 1.)) / n as f64).max(0.)
+}
+
+pub fn gui_handler<'a, T>(mut controller: Controller, keymap: Keymap, gui_recv: mpsc::Receiver<GuiHandlerEvent>, remote_recv: mpsc::Receiver<Remote>) -> Result<(), Error<T>> {
     let mut app = try!(ReconstructionApp::new());
     let mut ui = conrod::UiBuilder::new([WIDTH as f64, HEIGHT as f64]).build();
-    let ids = Ids::new(ui.widget_id_generator());
+    let mut ids = Ids::new(ui.widget_id_generator());
+    ids.level_sliders.resize(LEVELS.len(), &mut ui.widget_id_generator());
 
     let assets = find_folder::Search::KidsThenParents(3, 5).for_folder("assets").unwrap();
 
@@ -94,23 +259,9 @@ pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>,
 
     let mut text_vertex_data = Vec::new();
 
-    let mut glyph_cache = {
-        const SCALE_TOLERANCE: f32 = 0.1;
-        const POSITION_TOLERANCE: f32 = 0.1;
-        let cache = conrod::text::GlyphCache::new(WIDTH, HEIGHT, SCALE_TOLERANCE, POSITION_TOLERANCE);
-        cache
-    };
-
-    let mut text_texture_cache = {
-        let buffer_len = WIDTH as usize * HEIGHT as usize;
-        let init = vec![128; buffer_len];
-        let settings = TextureSettings::new();
-        let factory = &mut app.window.factory;
-        let texture = G2dTexture::from_memory_alpha(factory, &init, WIDTH, HEIGHT, &settings).unwrap();
-        texture
-    };
+    let (mut glyph_cache, mut text_texture_cache) = try!(text_caches(&mut app.window, WIDTH, HEIGHT));
         
-    let image_map = conrod::image::Map::new();
+    let mut image_map = conrod::image::Map::new();
 
     while let Some(event) = app.window.next() {
         while let Ok(handler_event) = gui_recv.try_recv() {
@@ -118,22 +269,103 @@ pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>,
                 GuiHandlerEvent::Devices(d) => app.devices = Some(d),
                 GuiHandlerEvent::InDevice(d) => app.in_device = Some(d),
                 GuiHandlerEvent::OutDevice(d) => app.out_device = Some(d),
+                GuiHandlerEvent::SampleRate(rate) => app.sample_rate = Some(rate),
+                GuiHandlerEvent::SampleRateRejected { rate, previous } => {
+                    println!("cannot analyse at {} Hz, going back to {} Hz", rate, previous);
+                    controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetSampleRate(previous)));
+                }
+                GuiHandlerEvent::InputChannels(n) => {
+                    app.input_channels = n;
+                    // The selected channel may be gone on the new device
+                    if app.input_channel_choice > n {
+                        app.input_channel_choice = 1;
+                        controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetInputChannels(vec![0])));
+                    }
+                }
+                GuiHandlerEvent::Stream(info) => {
+                    if let Some(ref info) = info {
+                        app.in_device = Some(info.in_device.0 as usize);
+                        app.out_device = Some(info.out_device.0 as usize);
+                    }
+                    app.stream = info;
+                    app.round_trip = None;
+                    let title = window_title(&app.stream, app.round_trip);
+                    app.window.set_title(title);
+                }
+                GuiHandlerEvent::Buffer { frames_per_buffer, latency, queue_depth } => {
+                    app.frames_per_buffer = Some(frames_per_buffer);
+                    app.latency = latency;
+                    app.queue_depth = Some(queue_depth);
+                }
+                GuiHandlerEvent::RoundTrip(round_trip) => {
+                    app.round_trip = round_trip;
+                    let title = window_title(&app.stream, app.round_trip);
+                    app.window.set_title(title);
+                }
+                GuiHandlerEvent::Segmenter(FeatureSource::Target, kind) => app.target_segmenter = Some(kind),
+                GuiHandlerEvent::Segmenter(FeatureSource::Capture, kind) => app.capture_segmenter = Some(kind),
+                GuiHandlerEvent::GrainLength(length) => app.grain_length = Some(length),
+                GuiHandlerEvent::GateLevel(level) => app.gate_level = level,
+                GuiHandlerEvent::Gated(report) => app.gated = Some(report),
+                GuiHandlerEvent::Dictionaries(dictionaries) => {
+                    ids.dictionary_toggles.resize(dictionaries.len(), &mut ui.widget_id_generator());
+                    ids.dictionary_weights.resize(dictionaries.len(), &mut ui.widget_id_generator());
+                    app.dictionaries = dictionaries;
+                }
+                // The library panel shows how much each dictionary supplied, which is all of
+                // the provenance there's room for
+                GuiHandlerEvent::Provenance(_) => { }
+                GuiHandlerEvent::Matcher(kind) => app.matcher = Some(kind),
+                GuiHandlerEvent::Selection(kind) => app.selection = Some(kind),
+                GuiHandlerEvent::Targets { targets, current } => {
+                    app.targets = targets;
+                    app.current_target = Some(current);
+                }
+                GuiHandlerEvent::Features(source, features) => {
+                    if let Some(texture) = feature_texture(&mut app.window.factory, &features) {
+                        let view = match source {
+                            FeatureSource::Target => &mut app.target_features,
+                            FeatureSource::Capture => &mut app.capture_features,
+                        };
+                        match *view {
+                            Some(image) => { image_map.get_mut(&image).map(|t| *t = texture); }
+                            None => { *view = Some(image_map.insert(texture)); }
+                        }
+                    }
+                }
             }
         }
 
+        while let Ok(remote) = remote_recv.try_recv() {
+            try!(controller.remote(remote));
+            app.threshold_text = controller.threshold.to_string();
+            app.depth_text = controller.depth.to_string();
+        }
+
+        // The glyph cache and its texture have to cover the whole window, so reallocate them
+        // whenever it changes size
+        if let Some((width, height)) = event.resize(|w, h| (w, h)) {
+            let (cache, texture) = try!(text_caches(&mut app.window, width, height));
+            glyph_cache = cache;
+            text_texture_cache = texture;
+        }
+
         if let Some(e) = convert(event.clone(), app.window.size().width as f64, app.window.size().height as f64) {
             use conrod::input::Button::*;
-            // Handle all the basic Raw events in the entire window
+            // Handle all the basic Raw events in the entire window. Shortcuts are ignored while
+            // a text box has the keyboard, so typing into it doesn't trigger them.
+            let typing = ui.global_input().current.widget_capturing_keyboard.is_some();
             if let conrod::event::Input::Press(button) = e {
                 match button {
-                    Keyboard(key) => {
-                        use conrod::input::Key;
-                        match key {
-                            Key::Space => try!(dictionary_commands_producer.send(DictionaryHandlerEvent::Refresh)
-                                               .map_err(|_| Error::String("Cannot send".to_string()))),
-                            Key::P => try!(dictionary_commands_producer.send(DictionaryHandlerEvent::Play)
-                                               .map_err(|_| Error::String("Cannot send".to_string()))),
-                            _ => { }
+                    Keyboard(key) if !typing => {
+                        match keymap.action(key) {
+                            Some(Action::ToggleHelp) => app.show_help = !app.show_help,
+                            Some(action) => {
+                                try!(controller.perform(action));
+                                app.threshold_text = controller.threshold.to_string();
+                                app.depth_text = controller.depth.to_string();
+                            }
+                            None => { }
                         }
                     },
                     _ => { }
@@ -145,82 +377,298 @@ pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>,
 
         event.update(|_| {
             let ui = &mut ui.set_widgets();
-            widget::Canvas::new().color(color::DARK_CHARCOAL).set(ids.canvas, ui);
 
-            // Add reconstruct button
+            // Split the window into stacked panels; only the visualizations stretch with the
+            // window height
+            widget::Canvas::new()
+                .flow_down(&[
+                    (ids.transport_panel, widget::Canvas::new().length(PANEL_HEIGHT).pad(PANEL_PAD).color(color::DARK_CHARCOAL)),
+                    (ids.devices_panel, widget::Canvas::new().length(3. * PANEL_HEIGHT - 2. * PANEL_PAD).pad(PANEL_PAD).color(color::CHARCOAL)),
+                    (ids.parameters_panel, widget::Canvas::new().length(PANEL_HEIGHT).pad(PANEL_PAD).color(color::DARK_CHARCOAL)),
+                    (ids.mix_panel, widget::Canvas::new().length(PANEL_HEIGHT).pad(PANEL_PAD).color(color::CHARCOAL)),
+                    (ids.library_panel, widget::Canvas::new().length(PANEL_HEIGHT).pad(PANEL_PAD).color(color::DARK_CHARCOAL)),
+                    (ids.visualizations_panel, widget::Canvas::new().pad(PANEL_PAD).color(color::DARK_CHARCOAL)),
+                ])
+                .set(ids.canvas, ui);
+
+            // Transport: DSP control, reconstruction and playback, then saving and loading
+            // capture dictionaries
+            let button_w = column_width(ui, ids.transport_panel, 7);
+            let button_h = row_height(ui, ids.transport_panel, 1);
+
+            if widget::Button::new()
+                .w_h(button_w, button_h)
+                .mid_left_of(ids.transport_panel)
+                .label("Start DSP")
+                .rgb(0., 1., 0.)
+                .set(ids.launch_audio_button, ui)
+                .was_clicked() 
+            {
+                controller.perform::<T>(Action::StartDsp);
+            }
+
             if widget::Button::new()
-                .w_h(200., 50.)
-                .middle()
+                .w_h(button_w, button_h)
+                .right_from(ids.launch_audio_button, MARGIN)
+                .label("Stop DSP")
+                .rgb(1., 0., 0.)
+                .set(ids.stop_audio_button, ui)
+                .was_clicked() 
+            {
+                controller.perform::<T>(Action::StopDsp);
+            }
+
+            if widget::Button::new()
+                .w_h(button_w, button_h)
+                .right_from(ids.stop_audio_button, MARGIN)
                 .label("Reconstruct")
                 .set(ids.reconstruct_button, ui)
                 .was_clicked() 
             {
-                dictionary_commands_producer.send(DictionaryHandlerEvent::Refresh);
+                controller.perform::<T>(Action::Reconstruct);
             }
 
-            // Add play button
             if widget::Button::new()
-                .w_h(200., 50.)
+                .w_h(button_w, button_h)
+                .right_from(ids.reconstruct_button, MARGIN)
                 .label("Play")
                 .set(ids.play_button, ui)
                 .was_clicked()
             {
-                dictionary_commands_producer.send(DictionaryHandlerEvent::Play);
+                controller.perform::<T>(Action::Play);
             }
 
+            for edit in widget::TextBox::new(&app.corpus_text)
+                .w_h(button_w, button_h)
+                .right_from(ids.play_button, MARGIN)
+                .set(ids.corpus_box, ui)
+            {
+                if let widget::text_box::Event::Update(new_text) = edit {
+                    app.corpus_text = new_text;
+                }
+            }
 
             if widget::Button::new()
-                .w_h(200., 50.)
-                .label("Start DSP")
-                .left_from(ids.reconstruct_button, 20.)
-                .rgb(0., 1., 0.)
-                .set(ids.launch_audio_button, ui)
-                .was_clicked() 
+                .w_h(button_w, button_h)
+                .right_from(ids.corpus_box, MARGIN)
+                .label("Save Corpus")
+                .set(ids.save_corpus_button, ui)
+                .was_clicked()
             {
-                audio_commands_producer.push(AudioHandlerEvent::Start);
+                if app.corpus_text.is_empty() {
+                    println!("enter the path to save the corpus to");
+                } else {
+                    controller.dictionary::<T>(DictionaryHandlerEvent::SaveCorpus(PathBuf::from(&app.corpus_text), None));
+                }
             }
 
             if widget::Button::new()
-                .w_h(200., 50.)
-                .label("Stop DSP")
-                .down_from(ids.launch_audio_button, 20.)
-                .rgb(1., 0., 0.)
-                .set(ids.stop_audio_button, ui)
-                .was_clicked() 
+                .w_h(button_w, button_h)
+                .right_from(ids.save_corpus_button, MARGIN)
+                .label("Load Corpus")
+                .set(ids.load_corpus_button, ui)
+                .was_clicked()
             {
-                audio_commands_producer.push(AudioHandlerEvent::Stop);
+                if app.corpus_text.is_empty() {
+                    println!("enter the path of a corpus to load");
+                } else {
+                    controller.dictionary::<T>(DictionaryHandlerEvent::LoadCorpus(PathBuf::from(&app.corpus_text)));
+                }
             }
 
+            // Devices, channels and rate on the first row, a WAV file to use in place of the
+            // input device on the second, buffering on the third
+            let list_w = column_width(ui, ids.devices_panel, 5);
+            let list_h = row_height(ui, ids.devices_panel, 3);
+            let column = |n: usize| n as f64 * (list_w + MARGIN);
             match app.devices {
                 Some(ref devices) => {
-                    let ds: Vec<&str> = devices.iter().map(|d| d.1.as_str()).collect();
-                    for idx in widget::DropDownList::new(&ds[..], app.in_device)
-                        .w_h(200., 30.)
+                    // Each list only offers devices that can do its job, so positions in the
+                    // list have to be mapped back to device indices
+                    let inputs: Vec<&DeviceEntry> = devices.iter().filter(|d| d.is_input()).collect();
+                    let outputs: Vec<&DeviceEntry> = devices.iter().filter(|d| d.is_output()).collect();
+                    let position = |list: &[&DeviceEntry], device: Option<usize>| {
+                        list.iter().position(|d| Some(d.index.0 as usize) == device)
+                    };
+
+                    let labels: Vec<String> = inputs.iter().map(|d| d.label()).collect();
+                    for idx in widget::DropDownList::new(&labels[..], position(&inputs[..], app.in_device))
+                        .w_h(list_w, list_h)
                         .label("Input Device")
-                        .up_from(ids.launch_audio_button, 20.)
+                        .top_left_of(ids.devices_panel)
                         .set(ids.in_devices_list, ui) 
                     {
-                        audio_commands_producer.push(AudioHandlerEvent::Setting(DeviceSetting::SetInDevice(idx as u32)));
-                        app.in_device = Some(idx);
+                        let DeviceIndex(device) = inputs[idx].index;
+                        controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetInDevice(device)));
+                        app.in_device = Some(device as usize);
                     }
 
-                    for idx in widget::DropDownList::new(&ds[..], app.out_device)
-                        .w_h(200., 30.)
+                    let labels: Vec<String> = outputs.iter().map(|d| d.label()).collect();
+                    for idx in widget::DropDownList::new(&labels[..], position(&outputs[..], app.out_device))
+                        .w_h(list_w, list_h)
                         .label("Output Device")
-                        .right_from(ids.in_devices_list, 20.)
+                        .top_left_with_margins_on(ids.devices_panel, 0., column(2))
                         .set(ids.out_devices_list, ui) 
                     {
-                        audio_commands_producer.push(AudioHandlerEvent::Setting(DeviceSetting::SetOutDevice(idx as u32)));
-                        app.out_device = Some(idx);
+                        let DeviceIndex(device) = outputs[idx].index;
+                        controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetOutDevice(device)));
+                        app.out_device = Some(device as usize);
                     }
                 }
                 None => { }
             }
+
+            // The first entry mixes every channel, the rest pick out one each
+            let mut channels = vec!["All Channels".to_string()];
+            channels.extend((1..app.input_channels + 1).map(|c| format!("Channel {}", c)));
+            for idx in widget::DropDownList::new(&channels[..], Some(app.input_channel_choice))
+                .w_h(list_w, list_h)
+                .label("Input Channels")
+                .top_left_with_margins_on(ids.devices_panel, 0., column(1))
+                .set(ids.in_channels_list, ui)
+            {
+                let selected = if idx == 0 { (0..app.input_channels).collect() } else { vec![idx - 1] };
+                controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetInputChannels(selected)));
+                app.input_channel_choice = idx;
+            }
+
+            let pan_label = format!("Pan {:.2}", app.pan);
+            if let Some(pan) = widget::Slider::new(app.pan, -1., 1.)
+                .w_h(list_w, list_h)
+                .label(&pan_label)
+                .top_left_with_margins_on(ids.devices_panel, 0., column(3))
+                .set(ids.pan_slider, ui)
+            {
+                controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetPan(pan)));
+                app.pan = pan;
+            }
+
+            let rates: Vec<String> = SAMPLE_RATES.iter().map(|r| format!("{} Hz", r)).collect();
+            let selected_rate = app.sample_rate.and_then(|rate| SAMPLE_RATES.iter().position(|r| *r == rate));
+            for idx in widget::DropDownList::new(&rates[..], selected_rate)
+                .w_h(list_w, list_h)
+                .label("Sample Rate")
+                .top_left_with_margins_on(ids.devices_panel, 0., column(4))
+                .set(ids.sample_rate_list, ui)
+            {
+                // The selection only sticks once the audio handler has accepted the rate
+                controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetSampleRate(SAMPLE_RATES[idx])));
+            }
+
+            for edit in widget::TextBox::new(&app.source_text)
+                .w_h(column(2) - MARGIN, list_h)
+                .top_left_with_margins_on(ids.devices_panel, list_h + MARGIN, 0.)
+                .set(ids.source_box, ui)
+            {
+                if let widget::text_box::Event::Update(new_text) = edit {
+                    app.source_text = new_text;
+                }
+            }
+
+            for edit in widget::TextBox::new(&app.speed_text)
+                .center_justify()
+                .w_h(list_w, list_h)
+                .right_from(ids.source_box, MARGIN)
+                .set(ids.speed_box, ui)
+            {
+                if let widget::text_box::Event::Update(new_text) = edit {
+                    app.speed_text = new_text;
+                }
+            }
+
+            if widget::Button::new()
+                .w_h(list_w, list_h)
+                .right_from(ids.speed_box, MARGIN)
+                .label("Load Source")
+                .set(ids.load_source_button, ui)
+                .was_clicked()
+            {
+                match app.speed_text.parse::<f64>() {
+                    Ok(speed) if !app.source_text.is_empty() => {
+                        controller.load_source::<T>(PathBuf::from(&app.source_text), speed);
+                    }
+                    Ok(_) => println!("enter the path of a WAV file to load"),
+                    Err(_) => println!("speed should be a multiple of realtime, or 0 for as fast as possible"),
+                }
+            }
+
+            if widget::Button::new()
+                .w_h(list_w, list_h)
+                .right_from(ids.load_source_button, MARGIN)
+                .label("Rescan Devices")
+                .set(ids.rescan_button, ui)
+                .was_clicked()
+            {
+                controller.audio::<T>(AudioHandlerEvent::Rescan);
+            }
+
+            // As with the rate, these show what the audio handler settled on
+            let buffers: Vec<String> = BUFFER_SIZES.iter().map(|b| format!("{} frames", b)).collect();
+            let selected_buffer = app.frames_per_buffer.and_then(|frames| BUFFER_SIZES.iter().position(|b| *b == frames));
+            for idx in widget::DropDownList::new(&buffers[..], selected_buffer)
+                .w_h(list_w, list_h)
+                .label("Buffer")
+                .bottom_left_of(ids.devices_panel)
+                .set(ids.buffer_list, ui)
+            {
+                controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetFramesPerBuffer(BUFFER_SIZES[idx])));
+            }
+
+            let mut latencies = vec!["Default Latency".to_string()];
+            latencies.extend(LATENCIES.iter().map(|l| format!("{} ms", l)));
+            let selected_latency = match app.latency {
+                None => Some(0),
+                Some(latency) => LATENCIES.iter().position(|l| (l / 1000. - latency).abs() < 1e-6).map(|idx| idx + 1),
+            };
+            for idx in widget::DropDownList::new(&latencies[..], selected_latency)
+                .w_h(list_w, list_h)
+                .label("Latency")
+                .right_from(ids.buffer_list, MARGIN)
+                .set(ids.latency_list, ui)
+            {
+                let latency = if idx == 0 { None } else { Some(LATENCIES[idx - 1] / 1000.) };
+                controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetLatency(latency)));
+            }
+
+            let depths: Vec<String> = QUEUE_DEPTHS.iter().map(|d| format!("{} blocks", d)).collect();
+            let selected_depth = app.queue_depth.and_then(|depth| QUEUE_DEPTHS.iter().position(|d| *d == depth));
+            for idx in widget::DropDownList::new(&depths[..], selected_depth)
+                .w_h(list_w, list_h)
+                .label("Queue")
+                .right_from(ids.latency_list, MARGIN)
+                .set(ids.queue_list, ui)
+            {
+                controller.audio::<T>(AudioHandlerEvent::Setting(DeviceSetting::SetQueueDepth(QUEUE_DEPTHS[idx])));
+            }
+
+            let round_trip = match app.round_trip {
+                Some(round_trip) => format!("Round trip {:.1} ms", round_trip * 1000.),
+                None => "Round trip unknown".to_string(),
+            };
+            widget::Text::new(&round_trip)
+                .w(column(2) - MARGIN)
+                .font_size(16)
+                .color(color::WHITE)
+                .right_from(ids.queue_list, MARGIN)
+                .set(ids.round_trip_text, ui);
             
+            // Partitioner parameters, each a label followed by its text box, then MIDI learn, the
+            // segmenters and the target
+            let param_w = column_width(ui, ids.parameters_panel, 9);
+            let param_h = row_height(ui, ids.parameters_panel, 1);
+
+            widget::Text::new("Threshold")
+                .w(param_w)
+                .font_size(16)
+                .color(color::WHITE)
+                .right_justify()
+                .mid_left_of(ids.parameters_panel)
+                .set(ids.threshold_label, ui);
+
             for edit in widget::TextBox::new(&app.threshold_text)
                 .center_justify()
-                .w_h(200., 50.)
-                .down_from(ids.play_button, 20.)
+                .w_h(param_w, param_h)
+                .right_from(ids.threshold_label, MARGIN)
                 .set(ids.threshold_box, ui) 
             {
                 match edit {
@@ -229,14 +677,24 @@ pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>,
                     }
                     widget::text_box::Event::Enter => {
                         if let Ok(new_threshold) = app.threshold_text.parse::<usize>() {
-                            dictionary_commands_producer.send(DictionaryHandlerEvent::SetThreshold(new_threshold));
+                            controller.set_threshold::<T>(new_threshold);
                         }
                     }
                 }
             }
 
+            widget::Text::new("Depth")
+                .w(param_w)
+                .font_size(16)
+                .color(color::WHITE)
+                .right_justify()
+                .right_from(ids.threshold_box, MARGIN)
+                .set(ids.depth_label, ui);
+
             for edit in widget::TextBox::new(&app.depth_text) 
                 .center_justify()
+                .w_h(param_w, param_h)
+                .right_from(ids.depth_label, MARGIN)
                 .set(ids.depth_box, ui)
             {
                 match edit {
@@ -245,11 +703,215 @@ pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>,
                     }
                     widget::text_box::Event::Enter => {
                         if let Ok(new_depth) = app.depth_text.parse::<usize>() {
-                            dictionary_commands_producer.send(DictionaryHandlerEvent::SetDepth(new_depth));
+                            controller.set_depth::<T>(new_depth);
                         }
                     }
                 }
             }
+
+            let targets = learnable_targets();
+            let learning = controller.midi_learning()
+                .and_then(|target| targets.iter().position(|t| *t == target.as_str()));
+            for idx in widget::DropDownList::new(&targets[..], learning)
+                .w_h(param_w, param_h)
+                .label("MIDI Learn")
+                .right_from(ids.depth_box, MARGIN)
+                .set(ids.midi_learn_list, ui)
+            {
+                if let Err(e) = controller.learn_midi::<T>(targets[idx]) {
+                    println!("{}", e);
+                }
+            }
+
+            let target_segmenters: Vec<String> = SEGMENTERS.iter().map(|k| format!("Target: {}", k.name())).collect();
+            let selected = app.target_segmenter.and_then(|kind| SEGMENTERS.iter().position(|k| *k == kind));
+            for idx in widget::DropDownList::new(&target_segmenters[..], selected)
+                .w_h(param_w, param_h)
+                .label("Target Segmenter")
+                .right_from(ids.midi_learn_list, MARGIN)
+                .set(ids.target_segmenter_list, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetSegmenter(FeatureSource::Target, SEGMENTERS[idx]));
+            }
+
+            let capture_segmenters: Vec<String> = SEGMENTERS.iter().map(|k| format!("Input: {}", k.name())).collect();
+            let selected = app.capture_segmenter.and_then(|kind| SEGMENTERS.iter().position(|k| *k == kind));
+            for idx in widget::DropDownList::new(&capture_segmenters[..], selected)
+                .w_h(param_w, param_h)
+                .label("Input Segmenter")
+                .right_from(ids.target_segmenter_list, MARGIN)
+                .set(ids.capture_segmenter_list, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetSegmenter(FeatureSource::Capture, SEGMENTERS[idx]));
+            }
+
+            let grains: Vec<String> = GRAIN_LENGTHS.iter().map(|g| format!("{} ms grains", g)).collect();
+            let selected = app.grain_length.and_then(|length| GRAIN_LENGTHS.iter().position(|g| (g / 1000. - length).abs() < 1e-6));
+            for idx in widget::DropDownList::new(&grains[..], selected)
+                .w_h(param_w, param_h)
+                .label("Grain Length")
+                .right_from(ids.capture_segmenter_list, MARGIN)
+                .set(ids.grain_list, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetGrainLength(GRAIN_LENGTHS[idx] / 1000.));
+            }
+
+            // Targets still loading can be picked, and are switched to once they're ready
+            let targets: Vec<String> = app.targets.iter()
+                .map(|t| if t.ready { t.name.clone() } else { format!("{} (loading)", t.name) })
+                .collect();
+            for idx in widget::DropDownList::new(&targets[..], app.current_target)
+                .w_h(param_w, param_h)
+                .label("Target")
+                .right_from(ids.grain_list, MARGIN)
+                .set(ids.target_list, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetTarget(idx));
+            }
+
+            // Mix: a fader for each level, then the gate on captured segments
+            let fader_w = column_width(ui, ids.mix_panel, LEVELS.len() + 1);
+            let fader_h = row_height(ui, ids.mix_panel, 1);
+            for (i, level) in LEVELS.iter().enumerate() {
+                // Gain can boost a quiet input; the others only cut
+                let max = if *level == Level::InputGain { 4. } else { 1. };
+                let label = format!("{} {:.2}", match *level {
+                    Level::InputGain => "Input Gain",
+                    Level::Monitor => "Monitor",
+                    Level::Reconstruction => "Reconstruction",
+                }, app.levels.level(*level));
+                if let Some(value) = widget::Slider::new(app.levels.level(*level), 0., max)
+                    .w_h(fader_w, fader_h)
+                    .label(&label)
+                    .top_left_with_margins_on(ids.mix_panel, 0., i as f64 * (fader_w + MARGIN))
+                    .set(ids.level_sliders[i], ui)
+                {
+                    controller.audio::<T>(AudioHandlerEvent::SetLevel(*level, value));
+                    app.levels.set_level(*level, value);
+                }
+            }
+
+            let gate_label = match app.gated {
+                Some(report) => format!("Gate {:.0} dB, {} dropped", app.gate_level, report.dropped),
+                None => format!("Gate {:.0} dB", app.gate_level),
+            };
+            if let Some(level) = widget::Slider::new(app.gate_level, -90., 0.)
+                .w_h(fader_w, fader_h)
+                .label(&gate_label)
+                .top_left_with_margins_on(ids.mix_panel, 0., LEVELS.len() as f64 * (fader_w + MARGIN))
+                .set(ids.gate_slider, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetGateLevel(level));
+                app.gate_level = level;
+            }
+
+            // Library: how segments are matched and chosen, then each dictionary's switch,
+            // showing how many segments of the last reconstruction it supplied, followed by its
+            // weight
+            let dict_w = column_width(ui, ids.library_panel, 2 * app.dictionaries.len().max(MIN_DICTIONARY_COLUMNS) + 2);
+            let dict_h = row_height(ui, ids.library_panel, 1);
+
+            let matchers: Vec<String> = MATCHERS.iter().map(|k| format!("Match: {}", k.name())).collect();
+            let selected = app.matcher.and_then(|kind| MATCHERS.iter().position(|k| *k == kind));
+            for idx in widget::DropDownList::new(&matchers[..], selected)
+                .w_h(dict_w, dict_h)
+                .label("Matcher")
+                .mid_left_of(ids.library_panel)
+                .set(ids.matcher_list, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetMatcher(MATCHERS[idx]));
+            }
+
+            let selections: Vec<String> = SELECTIONS.iter().map(|k| format!("Select: {}", k.name())).collect();
+            let selected = app.selection.and_then(|kind| SELECTIONS.iter().position(|k| *k == kind));
+            for idx in widget::DropDownList::new(&selections[..], selected)
+                .w_h(dict_w, dict_h)
+                .label("Selection")
+                .right_from(ids.matcher_list, MARGIN)
+                .set(ids.selection_list, ui)
+            {
+                controller.dictionary::<T>(DictionaryHandlerEvent::SetSelection(SELECTIONS[idx]));
+            }
+
+            if app.dictionaries.is_empty() {
+                widget::Text::new("Reconstruct or load a corpus to fill the library")
+                    .font_size(16)
+                    .color(color::WHITE)
+                    .right_from(ids.selection_list, MARGIN)
+                    .set(ids.library_text, ui);
+            }
+            for (i, info) in app.dictionaries.iter_mut().enumerate() {
+                let x = (2 * i + 2) as f64 * (dict_w + MARGIN);
+                let label = format!("{} {}/{}", info.name, info.used, info.segments);
+                for enabled in widget::Toggle::new(info.enabled)
+                    .w_h(dict_w, dict_h)
+                    .label(&label)
+                    .top_left_with_margins_on(ids.library_panel, 0., x)
+                    .set(ids.dictionary_toggles[i], ui)
+                {
+                    controller.dictionary::<T>(DictionaryHandlerEvent::SetDictionaryEnabled(info.name.clone(), enabled));
+                    info.enabled = enabled;
+                }
+
+                let label = format!("Weight {:.2}", info.weight);
+                if let Some(weight) = widget::Slider::new(info.weight, 0.1, MAX_DICTIONARY_WEIGHT)
+                    .w_h(dict_w, dict_h)
+                    .label(&label)
+                    .top_left_with_margins_on(ids.library_panel, 0., x + dict_w + MARGIN)
+                    .set(ids.dictionary_weights[i], ui)
+                {
+                    controller.dictionary::<T>(DictionaryHandlerEvent::SetDictionaryWeight(info.name.clone(), weight));
+                    info.weight = weight;
+                }
+            }
+
+            // MFCC heatmaps, time left to right and the first coefficient at the bottom. They
+            // share the visualization panel side by side, leaving room for a label above each.
+            let view_w = column_width(ui, ids.visualizations_panel, 2);
+            let view_h = (row_height(ui, ids.visualizations_panel, 1) - LABEL_HEIGHT).max(0.);
+
+            widget::Text::new("Target MFCCs")
+                .font_size(14)
+                .color(color::WHITE)
+                .top_left_of(ids.visualizations_panel)
+                .set(ids.target_features_label, ui);
+
+            widget::Text::new("Capture MFCCs")
+                .font_size(14)
+                .color(color::WHITE)
+                .top_left_with_margins_on(ids.visualizations_panel, 0., view_w + MARGIN)
+                .set(ids.capture_features_label, ui);
+
+            if let Some(image) = app.target_features {
+                widget::Image::new(image)
+                    .w_h(view_w, view_h)
+                    .bottom_left_of(ids.visualizations_panel)
+                    .set(ids.target_features, ui);
+            }
+
+            if let Some(image) = app.capture_features {
+                widget::Image::new(image)
+                    .w_h(view_w, view_h)
+                    .bottom_right_of(ids.visualizations_panel)
+                    .set(ids.capture_features, ui);
+            }
+
+            // Key bindings, drawn last so they sit on top of everything else
+            if app.show_help {
+                widget::Canvas::new()
+                    .floating(true)
+                    .w_h((ui.win_w * 0.6).max(300.), (ui.win_h * 0.7).max(300.))
+                    .middle_of(ids.canvas)
+                    .pad(20.)
+                    .color(color::BLACK.alpha(0.85))
+                    .set(ids.help_overlay, ui);
+
+                widget::Text::new(&keymap.help_text())
+                    .font_size(16)
+                    .color(color::WHITE)
+                    .top_left_of(ids.help_overlay)
+                    .set(ids.help_text, ui);
+            }
         });
 
         app.window.draw_2d(&event, |c, g| {
@@ -281,106 +943,249 @@ pub fn gui_handler<'a, T>(audio_commands_producer: Producer<AudioHandlerEvent>,
         });
     }
 
-    audio_commands_producer.push(AudioHandlerEvent::Quit);
-    dictionary_commands_producer.send(DictionaryHandlerEvent::Quit);
+    controller.quit();
     Ok(())
 }
 
-pub fn audio_handler<T>(audio_playback_queue: Arc<SegQueue<f64>>, audio_commands_receiver: Consumer<AudioHandlerEvent>, dict_prod: mpsc::Sender<DictionaryHandlerEvent>, gui_prod: mpsc::Sender<GuiHandlerEvent>) -> Result<(), Error<T>> {
+pub fn audio_handler<B: AudioBackend, T>(mut backend: B, config: &AudioConfig, audio_playback_queue: Arc<SegQueue<f64>>, audio_commands_receiver: Consumer<AudioHandlerEvent>, dict_prod: mpsc::Sender<DictionaryHandlerEvent>, gui_prod: mpsc::Sender<GuiHandlerEvent>, status_prod: mpsc::Sender<StatusEvent>) -> Result<(), Error<T>> {
     use AudioHandlerEvent::*;
     use DeviceSetting::*;
 
-    let pa = try!(PortAudio::new());
-    let mut settings: DuplexStreamSettings<f32, f32> = 
-        try!(pa.default_duplex_stream_settings(1, 1, 44100., BLOCK_SIZE as u32)
-             .map_err(Error::PortAudio));
+    if let Err(e) = backend.set_buffer::<T>(config.frames_per_buffer, config.latency) {
+        println!("cannot use configured buffer size: {}", e);
+    }
+    let mut queue_depth = clamp_queue_depth(config.queue_depth);
 
-    let devices = try!(pa.devices()).map(|d| {
-        let d = d.unwrap();
-        (d.0, d.1.name.to_string())
-    }).collect();
+    try!(send_devices(&backend, &gui_prod));
+    gui_prod.send(GuiHandlerEvent::SampleRate(backend.sample_rate()));
+    send_buffer(&backend, queue_depth, &gui_prod);
 
-    gui_prod.send(GuiHandlerEvent::InDevice(try!(pa.default_input_device()).0 as usize));
-    gui_prod.send(GuiHandlerEvent::OutDevice(try!(pa.default_output_device()).0 as usize));
-    gui_prod.send(GuiHandlerEvent::Devices(devices));
-    let mut stream: Option<portaudio::Stream<portaudio::NonBlocking, portaudio::Duplex<_, _>>> = None;
+    let mut running = false;
+    let mut source: Option<FileSource> = None;
+    let mut mix = ChannelMix::default();
+    let mut mix_prod: Option<Producer<ChannelMix>> = None;
+    // Round trip is reported about once a second while the stream runs, along with any input
+    // the callback had to drop because the dictionary couldn't keep up
+    let mut last_round_trip = time::Instant::now();
+    let dropped = Arc::new(AtomicUsize::new(0));
 
     'audio: loop { 
-        // Make sure the stream has not had an error
-        stream.as_ref().map(|s| s.is_active());
         match audio_commands_receiver.try_pop() {
             Some(Setting(setting)) => {
-                match setting {
+                let reopen = match setting {
                     SetInDevice(idx) => {
-                        let info = try!(pa.device_info(DeviceIndex(idx)));
-                        println!("Setting input device to {}", info.name);
-                        settings.in_params = StreamParameters::new(DeviceIndex(idx), 1, true, info.default_low_input_latency);
-                    },
+                        match backend.set_in_device::<T>(DeviceIndex(idx)) {
+                            Ok(()) => {
+                                gui_prod.send(GuiHandlerEvent::InputChannels(backend.channels().0));
+                                true
+                            }
+                            Err(e) => { println!("cannot use input device {}: {}", idx, e); false }
+                        }
+                    }
                     SetOutDevice(idx) => {
-                        let info = try!(pa.device_info(DeviceIndex(idx)));
-                        println!("Setting output device to {}", info.name);
-                        settings.out_params = StreamParameters::new(DeviceIndex(idx), 1, true, info.default_low_output_latency);
-                    },
-                }
-            }
-            Some(Start) => {
-                println!("starting stream with {:?}", &settings);
-
-                match stream {
-                    Some(ref mut s) => try!(s.start()),
-                    None => {
-                        // Take another reference to the Arc containing the playback stream
-                        let apq = audio_playback_queue.clone();
-                        // Initialize the command queues
-                        let (input_buffer_producer, input_buffer_receiver) = bounded_spsc_queue::make::<[f32; BLOCK_SIZE]>(65536);
-
-                        let callback = move |DuplexStreamCallbackArgs { in_buffer, out_buffer, .. }| {
-
-                            unsafe {
-                                assert_eq!(BLOCK_SIZE, in_buffer.len());
-                                let in_buffer: &[f32; BLOCK_SIZE] = transmute(in_buffer.as_ptr());
-                                match input_buffer_producer.try_push(*in_buffer) {
-                                    Some(_) => { println!("warning: sound buffer is full"); }
-                                    None => { }
+                        match backend.set_out_device::<T>(DeviceIndex(idx)) {
+                            Ok(()) => true,
+                            Err(e) => { println!("cannot use output device {}: {}", idx, e); false }
+                        }
+                    }
+                    SetInputChannels(channels) => {
+                        mix.set_inputs(&channels[..]);
+                        println!("capturing input channels {:?}", mix.input_channels().iter().map(|c| c + 1).collect::<Vec<usize>>());
+                        if let Some(ref p) = mix_prod { p.try_push(mix); }
+                        false
+                    }
+                    SetPan(pan) => {
+                        mix.pan = pan;
+                        if let Some(ref p) = mix_prod { p.try_push(mix); }
+                        false
+                    }
+                    SetSampleRate(rate) => {
+                        match backend.set_sample_rate::<T>(rate) {
+                            Ok(()) => {
+                                // An open stream is still running at the old rate
+                                if backend.is_open() {
+                                    try!(backend.close());
+                                    dict_prod.send(DictionaryHandlerEvent::InputBuffer(None));
+                                    gui_prod.send(GuiHandlerEvent::Stream(None));
+                                    if running {
+                                        running = false;
+                                        status_prod.send(StatusEvent::Dsp(false));
+                                    }
                                 }
+                                dict_prod.send(DictionaryHandlerEvent::SampleRate(rate));
+                                gui_prod.send(GuiHandlerEvent::SampleRate(rate));
                             }
+                            Err(e) => println!("cannot use {} Hz: {}", rate, e),
+                        }
+                        false
+                    }
+                    SetFramesPerBuffer(frames) => {
+                        let (_, latency) = backend.buffer();
+                        let changed = match backend.set_buffer::<T>(frames, latency) {
+                            Ok(()) => true,
+                            Err(e) => { println!("cannot use {} frames per buffer: {}", frames, e); false }
+                        };
+                        send_buffer(&backend, queue_depth, &gui_prod);
+                        changed
+                    }
+                    SetLatency(latency) => {
+                        let (frames, _) = backend.buffer();
+                        let changed = match backend.set_buffer::<T>(frames, latency) {
+                            Ok(()) => true,
+                            Err(e) => { println!("cannot use that latency: {}", e); false }
+                        };
+                        send_buffer(&backend, queue_depth, &gui_prod);
+                        changed
+                    }
+                    SetQueueDepth(depth) => {
+                        let depth = clamp_queue_depth(depth);
+                        let changed = depth != queue_depth;
+                        queue_depth = depth;
+                        send_buffer(&backend, queue_depth, &gui_prod);
+                        changed
+                    }
+                };
 
-                            for s in out_buffer.iter_mut() {
-                                match apq.try_pop() {
-                                    Some(input) => { *s = input as f32 }
-                                    None => { *s = 0. }
+                // An open stream keeps the devices and buffers it was opened with, so replace
+                // it with a new one and carry on where it left off
+                if reopen && backend.is_open() {
+                    println!("reopening stream");
+                    try!(backend.close());
+                    match open_stream::<B, T>(&mut backend, &audio_playback_queue, mix, queue_depth, &dropped) {
+                        Ok((input_buffer_receiver, producer)) => {
+                            mix_prod = Some(producer);
+                            dict_prod.send(DictionaryHandlerEvent::SwapInput(input_buffer_receiver));
+                            if running {
+                                if let Err(e) = backend.start::<T>() {
+                                    println!("could not restart the stream: {}", e);
+                                    running = false;
+                                    status_prod.send(StatusEvent::Dsp(false));
                                 }
                             }
+                        }
+                        Err(e) => {
+                            println!("could not reopen the stream: {}", e);
+                            dict_prod.send(DictionaryHandlerEvent::InputBuffer(None));
+                            if running {
+                                running = false;
+                                status_prod.send(StatusEvent::Dsp(false));
+                            }
+                        }
+                    }
+                    gui_prod.send(GuiHandlerEvent::Stream(backend.stream_info()));
+                }
+            }
+            Some(SetLevel(level, value)) => {
+                mix.set_level(level, value);
+                if let Some(ref p) = mix_prod { p.try_push(mix); }
+            }
+            Some(Start) => {
+                println!("starting stream");
+                // Live input takes over from a source file
+                source = None;
 
-                            Continue
-                        };
+                if !backend.is_open() {
+                    match open_stream::<B, T>(&mut backend, &audio_playback_queue, mix, queue_depth, &dropped) {
+                        Ok((input_buffer_receiver, producer)) => {
+                            mix_prod = Some(producer);
+                            // Push the new stream receiver to the dictionary
+                            dict_prod.send(DictionaryHandlerEvent::SourceFile(None));
+                            dict_prod.send(DictionaryHandlerEvent::InputBuffer(Some(input_buffer_receiver)));
+                            gui_prod.send(GuiHandlerEvent::Stream(backend.stream_info()));
+                        }
+                        Err(e) => {
+                            // Most likely a device has been unplugged, so look again
+                            println!("could not open stream: {}", e);
+                            match backend.rescan::<T>() {
+                                Ok(()) => if let Err(e) = send_devices::<B, T>(&backend, &gui_prod) {
+                                    println!("could not list devices: {}", e);
+                                },
+                                Err(e) => println!("could not rescan devices: {}", e),
+                            }
+                            continue 'audio;
+                        }
+                    }
+                }
 
-                        // settings is copy, so sending it is totally okay in this instance
-                        stream = Some(try!(pa.open_non_blocking_stream(settings, callback)));
-                        // Push the new stream receiver to the dictionary
-                        dict_prod.send(DictionaryHandlerEvent::InputBuffer(Some(input_buffer_receiver)));
-                        try!(stream.as_mut().unwrap().start());
-                    },
+                if let Err(e) = backend.start::<T>() {
+                    println!("could not start the stream: {}", e);
+                    continue 'audio;
                 }
+                running = true;
+                status_prod.send(StatusEvent::Dsp(true));
             }
             Some(Stop) => {
-                match stream {
-                    Some(ref mut s) => {
-                        println!("stopping stream");
-                        try!(s.stop());
+                if let Some(mut s) = source.take() {
+                    println!("stopping source file");
+                    s.stop();
+                    dict_prod.send(DictionaryHandlerEvent::InputBuffer(None));
+                } else if backend.is_open() {
+                    println!("stopping stream");
+                    try!(backend.stop());
+                    running = false;
+                    dict_prod.send(DictionaryHandlerEvent::InputBuffer(None));
+                    status_prod.send(StatusEvent::Dsp(false));
+                } else {
+                    println!("Stream not enabled");
+                }
+            }
+            Some(LoadSource(path, speed)) => {
+                // The file replaces live input entirely. Closing the stream means the next
+                // Start opens a fresh one and hands its input back to the dictionary.
+                if backend.is_open() {
+                    try!(backend.close());
+                    gui_prod.send(GuiHandlerEvent::Stream(None));
+                    if running {
+                        running = false;
+                        status_prod.send(StatusEvent::Dsp(false));
+                    }
+                }
+                source = None;
+
+                match FileSource::open::<T>(&path, speed, backend.sample_rate()) {
+                    Ok((s, input_buffer_receiver)) => {
+                        source = Some(s);
+                        dict_prod.send(DictionaryHandlerEvent::SourceFile(Some(path.clone())));
+                        dict_prod.send(DictionaryHandlerEvent::InputBuffer(Some(input_buffer_receiver)));
+                    }
+                    Err(e) => println!("could not load source file: {}", e),
+                }
+            }
+            Some(Rescan) => {
+                if running {
+                    println!("stop DSP before rescanning devices");
+                } else {
+                    // A stopped stream still holds on to its devices
+                    if backend.is_open() {
+                        try!(backend.close());
                         dict_prod.send(DictionaryHandlerEvent::InputBuffer(None));
-                    },
-                    None => println!("Stream not enabled"),
+                        gui_prod.send(GuiHandlerEvent::Stream(None));
+                    }
+                    match backend.rescan::<T>() {
+                        Ok(()) => if let Err(e) = send_devices::<B, T>(&backend, &gui_prod) {
+                            println!("could not list devices: {}", e);
+                        },
+                        Err(e) => println!("could not rescan devices: {}", e),
+                    }
                 }
             }
             Some(Quit) => { 
-                match stream {
-                    Some(ref mut s) => try!(s.stop()),
-                    None => println!("Stream not enabled"),
+                if backend.is_open() {
+                    try!(backend.stop());
+                } else {
+                    println!("Stream not enabled");
                 }
                 break 'audio;
             }
             None => { 
+                if running && last_round_trip.elapsed() >= time::Duration::from_secs(1) {
+                    gui_prod.send(GuiHandlerEvent::RoundTrip(backend.round_trip()));
+                    let blocks = dropped.swap(0, Ordering::SeqCst);
+                    if blocks > 0 {
+                        println!("warning: sound buffer was full, dropped {} blocks", blocks);
+                    }
+                    last_round_trip = time::Instant::now();
+                }
                 thread::sleep(time::Duration::from_millis(10));
             }
         }
@@ -389,91 +1194,532 @@ pub fn audio_handler<T>(audio_playback_queue: Arc<SegQueue<f64>>, audio_commands
     Ok(())
 }
 
-pub fn dictionary_handler(audio_playback_queue: Arc<SegQueue<f64>>, dictionary_commands_receiver: mpsc::Receiver<DictionaryHandlerEvent>) {
-    // Read in the target file and create sequence using timestamps
-    let assets = find_folder::Search::KidsThenParents(3, 5).for_folder("assets").unwrap();
+/// Tells the frontend which devices there are and which are in use
+fn send_devices<B: AudioBackend, T>(backend: &B, gui_prod: &mpsc::Sender<GuiHandlerEvent>) -> Result<(), Error<T>> {
+    let devices = try!(backend.devices());
+    let (in_device, out_device) = backend.current_devices();
 
-    let target_sequence = {
-        use std::borrow::Cow;
+    gui_prod.send(GuiHandlerEvent::InDevice(in_device.0 as usize));
+    gui_prod.send(GuiHandlerEvent::OutDevice(out_device.0 as usize));
+    gui_prod.send(GuiHandlerEvent::Devices(devices));
+    gui_prod.send(GuiHandlerEvent::InputChannels(backend.channels().0));
+    Ok(())
+}
 
-        let target = Arc::new(Sound::from_path(&assets.join("inventing.wav")).unwrap());
-        println!("Source is {} samples", target.samples().len());
+/// Tells the frontend the buffer settings the backend actually ended up with
+fn send_buffer<B: AudioBackend>(backend: &B, queue_depth: usize, gui_prod: &mpsc::Sender<GuiHandlerEvent>) {
+    let (frames_per_buffer, latency) = backend.buffer();
+    gui_prod.send(GuiHandlerEvent::Buffer {
+        frames_per_buffer: frames_per_buffer,
+        latency: latency,
+        queue_depth: queue_depth,
+    });
+}
 
-        // Only need mutable access for the training
-        let partitioner = {
-            let mut partitioner = Partitioner::new(Cow::Borrowed(&target));
-            partitioner = partitioner.threshold(DEFAULT_THRESHOLD).depth(DEFAULT_DEPTH);
-            partitioner.train();
-            partitioner
-        };
+/// Opens a stream on the backend's current devices. Returns the queue its input arrives on,
+/// `queue_depth` blocks long, and one for changing its channel mix while it runs. Blocks that
+/// don't fit in the queue are counted in `dropped`.
+fn open_stream<B: AudioBackend, T>(backend: &mut B, audio_playback_queue: &Arc<SegQueue<f64>>, mix: ChannelMix, queue_depth: usize, dropped: &Arc<AtomicUsize>) -> Result<(Consumer<[f32; BLOCK_SIZE]>, Producer<ChannelMix>), Error<T>> {
+    // Take another reference to the Arc containing the playback stream
+    let apq = audio_playback_queue.clone();
+    let dropped = dropped.clone();
+    // Initialize the command queues
+    let (input_buffer_producer, input_buffer_receiver) = bounded_spsc_queue::make::<[f32; BLOCK_SIZE]>(queue_depth);
+
+    // Channel selection, panning and levels can change while the stream runs
+    let (mix_producer, mix_receiver) = bounded_spsc_queue::make::<ChannelMix>(16);
+    let mut stream_mix = mix;
+    let (in_channels, out_channels) = backend.channels();
+
+    // Callbacks come in whatever size the buffer is set to, but the dictionary takes input a
+    // block at a time, so it's gathered up here. Both buffers are allocated up front so the
+    // audio thread doesn't have to.
+    let mut mono = Vec::<f32>::with_capacity(MAX_FRAMES_PER_BUFFER as usize);
+    let mut block = [0f32; BLOCK_SIZE];
+    let mut filled = 0;
+
+    let callback: Callback = Box::new(move |in_buffer: &[f32], out_buffer: &mut [f32]| {
+        while let Some(m) = mix_receiver.try_pop() {
+            stream_mix = m;
+        }
+
+        mono.resize(in_buffer.len() / in_channels, 0.);
+        stream_mix.mix_input(in_buffer, in_channels, &mut mono[..]);
+        for s in mono.iter() {
+            block[filled] = *s;
+            filled += 1;
+            if filled == BLOCK_SIZE {
+                if input_buffer_producer.try_push(block).is_some() {
+                    dropped.fetch_add(1, Ordering::SeqCst);
+                }
+                filled = 0;
+            }
+        }
+
+        for (frame, input) in out_buffer.chunks_mut(out_channels).zip(mono.iter()) {
+            let s = match apq.try_pop() {
+                Some(output) => output as f32,
+                None => 0.,
+            };
+            stream_mix.spread_output(stream_mix.output_sample(s, *input), frame);
+        }
+    });
 
-        let rows = target.mfccs().len() / NCOEFFS;
-        let cols = NCOEFFS;
-        let data = Matrix::new(rows, cols, target.mfccs().clone());
-        let predictions = partitioner.predict(&data).unwrap();
-        let splits = partitioner.partition(predictions).unwrap();
+    try!(backend.open(callback));
+    Ok((input_buffer_receiver, mix_producer))
+}
 
-        println!("Found {} splits in original sound", splits.len());
-        let dict = SoundDictionary::from_segments(&target, &splits[..]);
-        let sequence = SoundSequence::new(dict.sounds);
-        Arc::new(sequence)
+/// Writes mono samples out as a 32-bit float WAV
+fn write_wav<T>(path: &Path, samples: &[f64], sample_rate: u32) -> Result<(), Error<T>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
     };
+    let mut writer = try!(hound::WavWriter::create(path, spec)
+                          .map_err(|e| Error::String(format!("{}", e))));
+    for s in samples {
+        try!(writer.write_sample(*s as f32).map_err(|e| Error::String(format!("{}", e))));
+    }
+    try!(writer.finalize().map_err(|e| Error::String(format!("{}", e))));
+    Ok(())
+}
+
+/// Shows the current target and what's become of the rest of the playlist
+fn show_target(playlist: &Playlist, library: &Library, gui_prod: &mpsc::Sender<GuiHandlerEvent>, status_prod: &mpsc::Sender<StatusEvent>) {
+    gui_prod.send(GuiHandlerEvent::Features(FeatureSource::Target, playlist.target().features.clone()));
+    gui_prod.send(GuiHandlerEvent::Targets { targets: playlist.info(), current: playlist.current() });
+    status_prod.send(StatusEvent::Target(playlist.current()));
+    status_prod.send(StatusEvent::Segments { target: playlist.target().units.len(), capture: library.enabled_segments() });
+}
+
+/// The latest reconstruction of the target and where each of its segments came from
+struct Reconstructed {
+    sound: Sound,
+    provenance: Vec<Provenance>,
+}
+
+/// Reconstructs the target from the library, replacing `output` if it can, and reports how it
+/// went to the frontends
+fn reconstruct(library: &mut Library, target: &Target, matching: &MatchingConfig, sample_rate: f64, output: &mut Reconstructed, gui_prod: &mpsc::Sender<GuiHandlerEvent>, status_prod: &mpsc::Sender<StatusEvent>) {
+    let result = library.reconstruct(&target.units[..], target.hop, &*matching.matcher(), &matching.selection);
+    gui_prod.send(GuiHandlerEvent::Dictionaries(library.info()));
+    status_prod.send(StatusEvent::Segments { target: target.units.len(), capture: library.enabled_segments() });
+    match result {
+        Ok(mut reconstruction) => {
+            for p in reconstruction.provenance.iter_mut() {
+                p.target_label = target.labels[p.target].clone();
+            }
+            println!("samps: {}", reconstruction.samples.len());
+            gui_prod.send(GuiHandlerEvent::Provenance(reconstruction.provenance.clone()));
+            output.sound = Sound::from_samples(reconstruction.samples, sample_rate, None, None);
+            output.provenance = reconstruction.provenance;
+        }
+        Err(e) => println!("could not reconstruct: {}", e),
+    }
+}
+
+/// The file the input at `sample` in the capture came from
+fn source_at(runs: &[(usize, Option<String>)], sample: usize) -> Option<String> {
+    runs.iter().rev().find(|&&(start, _)| start <= sample).and_then(|&(_, ref source)| source.clone())
+}
+
+/// What the dictionary handler starts out with, besides the targets and its channels
+pub struct DictionaryContext {
+    pub recording: RecordingConfig,
+    pub segmentation: SegmentationConfig,
+    pub matching: MatchingConfig,
+    /// Names the recordings made this run
+    pub session: String,
+    pub sample_rate: f64,
+}
+
+pub fn dictionary_handler(audio_playback_queue: Arc<SegQueue<f64>>, dictionary_commands_receiver: mpsc::Receiver<DictionaryHandlerEvent>, gui_prod: mpsc::Sender<GuiHandlerEvent>, status_prod: mpsc::Sender<StatusEvent>, mut playlist: Playlist, context: DictionaryContext) {
+    let DictionaryContext { recording: recording_config, mut segmentation, mut matching, session, mut sample_rate } = context;
+    let mut shown_target = playlist.current();
 
     use DictionaryHandlerEvent::*;
 
-    let mut sound = Sound::from_samples(Vec::<f64>::with_capacity(65536), 44100., None, None);
+    let mut sound = Sound::from_samples(Vec::<f64>::with_capacity(65536), sample_rate, None, None);
     let mut buf = Vec::<f64>::with_capacity(65536);
+    // Input goes into the capture with the offset taken out, so the gate only hears the sound
+    // itself
+    let mut dc_blocker = DcBlocker::new();
     let mut depth = DEFAULT_DEPTH;
     let mut threshold = DEFAULT_THRESHOLD;
-    let mut other_sound = Sound::from_samples(Vec::<f64>::new(), 44100., None, None);
+    let mut output = Reconstructed {
+        sound: Sound::from_samples(Vec::<f64>::new(), sample_rate, None, None),
+        provenance: Vec::new(),
+    };
 
-    let target = target_sequence.to_sound();
-    let mut partitioner = Partitioner::new(Cow::Owned(target))
-        .threshold(threshold).depth(depth);
-    partitioner.train();
+    // Only trained once it's needed to split the input, and in the background so commands
+    // keep being handled in the meantime. A refresh asked for while it trains waits for it.
+    let mut model: Option<ModelSegmenter<'static>> = None;
+    let mut trainer: Option<ModelTrainer> = None;
+    let mut refresh_waiting = false;
+    gui_prod.send(GuiHandlerEvent::Segmenter(FeatureSource::Target, segmentation.target));
+    gui_prod.send(GuiHandlerEvent::Segmenter(FeatureSource::Capture, segmentation.capture));
+    gui_prod.send(GuiHandlerEvent::GrainLength(segmentation.grain.length));
+    gui_prod.send(GuiHandlerEvent::GateLevel(segmentation.gate.level));
 
     let mut input_buffer_receiver: Option<Consumer<[f32; BLOCK_SIZE]>> = None;
+    let mut previous_input: Option<Consumer<[f32; BLOCK_SIZE]>> = None;
+    let mut playing = false;
+    let mut looping = false;
+    // Each run of input gets its own recording, opened when the first block arrives
+    let mut recording = recording_config.always;
+    let mut recorder: Option<Recorder> = None;
+    // Where each run of input starts in the capture and the file it came from, if any
+    let mut runs: Vec<(usize, Option<String>)> = Vec::new();
+    let mut next_source: Option<String> = None;
+    // The capture dictionary and any corpora loaded from disk, and where each segment of the
+    // latest reconstruction came from
+    let mut library = Library::new();
+    gui_prod.send(GuiHandlerEvent::Matcher(matching.metric));
+    gui_prod.send(GuiHandlerEvent::Selection(matching.selection.method));
+    show_target(&playlist, &library, &gui_prod, &status_prod);
 
     loop {
-        while let Some(Some(ref incoming_sound)) = input_buffer_receiver.as_mut().map(|r| r.try_pop()) {
+        // Switch over once the target asked for is ready, which may be straight away
+        let finished = playlist.poll();
+        if playlist.current() != shown_target {
+            shown_target = playlist.current();
+            println!("switched to target {}", playlist.target().name);
+            model = None;
+            trainer = None;
+            show_target(&playlist, &library, &gui_prod, &status_prod);
+            if library.enabled_segments() > 0 {
+                reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+            }
+        } else if finished {
+            gui_prod.send(GuiHandlerEvent::Targets { targets: playlist.info(), current: playlist.current() });
+        }
+
+        let trained = trainer.as_ref().and_then(|t| t.try_recv());
+        if let Some(mut trained) = trained {
+            println!("the partitioner is trained");
+            // Either could have changed while it was training
+            trained.set_threshold(threshold);
+            trained.set_depth(depth);
+            model = Some(trained);
+            trainer = None;
+        }
+
+        // Start over once the last pass has been played out, or report that it's finished
+        if playing && audio_playback_queue.is_empty() {
+            if looping {
+                for s in output.sound.samples() {
+                    audio_playback_queue.push(*s);
+                }
+            } else {
+                playing = false;
+                status_prod.send(StatusEvent::Playing(false));
+            }
+        }
+
+        loop {
+            // Blocks left behind by a stream that's been replaced come first
+            let previous = previous_input.as_mut().and_then(|r| r.try_pop());
+            let block = match previous {
+                Some(block) => Some(block),
+                None => {
+                    previous_input = None;
+                    input_buffer_receiver.as_mut().and_then(|r| r.try_pop())
+                }
+            };
+            let incoming_sound = match block {
+                Some(block) => block,
+                None => break,
+            };
+
+            if recording && recorder.is_none() {
+                match Recorder::start::<()>(&recording_config.directory, &session, sample_rate as u32) {
+                    Ok(r) => recorder = Some(r),
+                    Err(e) => {
+                        println!("could not start recording: {}", e);
+                        recording = false;
+                    }
+                }
+            }
+            if let Some(ref r) = recorder {
+                r.push(&incoming_sound);
+            }
+
             for s in incoming_sound.iter() {
                 buf.push(*s as f64);
             }
+            dc_blocker.process(&mut buf[..]);
             sound.push_samples(&buf[..]);
             buf.clear();
         };
 
-        match dictionary_commands_receiver.try_recv() {
+        let command = if refresh_waiting && model.is_some() {
+            refresh_waiting = false;
+            Ok(Refresh)
+        } else {
+            dictionary_commands_receiver.try_recv()
+        };
+
+        match command {
+            Ok(Refresh) if segmentation.capture == SegmenterKind::Model && model.is_none() => {
+                if trainer.is_none() {
+                    println!("Training the partitioner on the target");
+                    trainer = Some(ModelTrainer::spawn(playlist.target().sequence.to_sound(), threshold, depth));
+                }
+                println!("the capture will be split once the partitioner is trained");
+                refresh_waiting = true;
+            }
             Ok(Refresh) => {
-                let rows = sound.mfccs().len() / NCOEFFS;
-                let cols = NCOEFFS;
-                let data = Matrix::new(rows, cols, sound.mfccs().clone());
-                let predictions = partitioner.predict(&data).unwrap();
-                let splits = partitioner.partition(predictions).unwrap();
-                if splits.len() == 0 { 
-                    println!("no possible partitions found");
-                } else {
-                    let dict = SoundDictionary::from_segments(&sound, &splits[..]);
-                    println!("nsegs: {}", dict.sounds.len());
-                    other_sound = target_sequence.clone_from_dictionary(&dict).unwrap().to_sound();
-                    println!("samps: {}", other_sound.samples().len());
+                gui_prod.send(GuiHandlerEvent::Features(FeatureSource::Capture, FeatureMatrix::from_mfccs(sound.mfccs())));
+                // Only the model needs to be kept between refreshes; the others are cheap to make
+                let onset;
+                let grain;
+                let segmenter: &Segmenter = match segmentation.capture {
+                    SegmenterKind::Model => model.as_ref().unwrap(),
+                    SegmenterKind::Onset => {
+                        onset = OnsetSegmenter::new(segmentation.onset.clone(), sample_rate);
+                        &onset
+                    }
+                    SegmenterKind::Grain => {
+                        grain = GrainSegmenter::new(segmentation.grain.clone(), sample_rate);
+                        &grain
+                    }
+                };
+                match segmenter.segment(&sound) {
+                    Ok(ref splits) if splits.is_empty() => println!("no possible partitions found"),
+                    Ok(splits) => {
+                        let gate = Gate::new(segmentation.gate.clone(), sample_rate);
+                        let (splits, report) = gate.apply(sound.samples(), &splits[..]);
+                        println!("gate kept {} segments ({} trimmed) and dropped {}", report.kept, report.trimmed, report.dropped);
+                        gui_prod.send(GuiHandlerEvent::Gated(report));
+                        status_prod.send(StatusEvent::Gated { kept: report.kept, dropped: report.dropped });
+                        if splits.is_empty() {
+                            println!("nothing got through the gate");
+                        } else {
+                            let dict = SoundDictionary::from_segments(&sound, &splits[..]);
+                            let sources: Vec<Option<String>> = splits.iter()
+                                .map(|&(start, _)| source_at(&runs[..], duration_sample(start, sample_rate)))
+                                .collect();
+                            println!("nsegs: {}", dict.sounds.len());
+                            library.insert(CAPTURE_NAME, Corpus::from_dictionary(&dict, &splits[..], &sources[..], sample_rate));
+                            reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                        }
+                    }
+                    Err(e) => println!("could not segment input: {}", e),
                 }
             }
             Ok(Play) => {
-                for s in other_sound.samples() {
+                for s in output.sound.samples() {
                     audio_playback_queue.push(*s);
                 }
+                playing = true;
+                status_prod.send(StatusEvent::Playing(true));
+            }
+            Ok(Stop) => {
+                while let Some(_) = audio_playback_queue.try_pop() { }
+                if playing {
+                    playing = false;
+                    status_prod.send(StatusEvent::Playing(false));
+                }
+            }
+            Ok(Export(path)) => {
+                match write_wav::<()>(&path, output.sound.samples(), sample_rate as u32) {
+                    Ok(_) => println!("exported reconstruction to {}", path.display()),
+                    Err(e) => println!("could not export reconstruction: {}", e),
+                }
+                let provenance_path = path.with_extension("provenance.toml");
+                match save_provenance::<()>(&output.provenance[..], &provenance_path) {
+                    Ok(()) => println!("wrote provenance to {}", provenance_path.display()),
+                    Err(e) => println!("could not write provenance: {}", e),
+                }
+            }
+            Ok(SaveCorpus(path, label)) => {
+                match library.get(CAPTURE_NAME) {
+                    Some(entry) => {
+                        let mut corpus = entry.corpus.clone();
+                        if let Some(ref label) = label {
+                            corpus.label(label);
+                        }
+                        match corpus.save::<()>(&path) {
+                            Ok(()) => println!("saved {} segments to {}", corpus.segments.len(), path.display()),
+                            Err(e) => println!("could not save corpus: {}", e),
+                        }
+                    }
+                    None => println!("nothing to save yet, reconstruct first"),
+                }
+            }
+            Ok(LoadCorpus(path)) => {
+                match Corpus::load::<()>(&path) {
+                    Ok(mut loaded) => {
+                        if loaded.sample_rate != sample_rate {
+                            println!("Resampling corpus from {} Hz to {} Hz", loaded.sample_rate, sample_rate);
+                            loaded.resample(sample_rate);
+                        }
+                        let name = corpus_name(&path);
+                        println!("loaded {} segments from {} as {}", loaded.segments.len(), path.display(), name);
+                        library.insert(&name, loaded);
+                        reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                    }
+                    Err(e) => println!("could not load corpus: {}", e),
+                }
+            }
+            Ok(SetDictionaryEnabled(name, enabled)) => {
+                match library.set_enabled(&name, enabled) {
+                    Ok(()) => {
+                        reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(SetDictionaryWeight(name, weight)) => {
+                match library.set_weight(&name, weight) {
+                    Ok(()) => {
+                        reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(RemoveDictionary(name)) => {
+                match library.remove(&name) {
+                    Ok(()) => {
+                        println!("removed {}", name);
+                        reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Ok(SetMatcher(kind)) => {
+                matching.metric = kind;
+                gui_prod.send(GuiHandlerEvent::Matcher(kind));
+                if library.enabled_segments() > 0 {
+                    reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                }
+            }
+            Ok(SetSelection(kind)) => {
+                matching.selection.method = kind;
+                gui_prod.send(GuiHandlerEvent::Selection(kind));
+                if library.enabled_segments() > 0 {
+                    reconstruct(&mut library, playlist.target(), &matching, sample_rate, &mut output, &gui_prod, &status_prod);
+                }
+            }
+            Ok(SetTarget(idx)) => {
+                if let Err(e) = playlist.select(idx) {
+                    println!("{}", e);
+                }
+            }
+            Ok(SourceFile(path)) => {
+                next_source = path.map(|p| p.display().to_string());
+            }
+            Ok(Clear) => {
+                runs.clear();
+                sound = Sound::from_samples(Vec::<f64>::with_capacity(65536), sample_rate, None, None);
+                dc_blocker = DcBlocker::new();
+            }
+            Ok(SetLoop(x)) => {
+                looping = x;
+            }
+            Ok(SetRecording(x)) => {
+                recording = x;
+                if !recording {
+                    recorder = None;
+                }
             }
             Ok(SetThreshold(x)) => { 
                 threshold = x; 
-                partitioner = partitioner.threshold(threshold);
+                if let Some(ref mut model) = model {
+                    model.set_threshold(threshold);
+                }
             }
             Ok(SetDepth(x)) => { 
                 depth = x; 
-                partitioner = partitioner.depth(depth);
+                if let Some(ref mut model) = model {
+                    model.set_depth(depth);
+                }
+            }
+            Ok(SampleRate(rate)) => {
+                if rate != sample_rate {
+                    // Everything is analysed at the device rate, so the target has to be
+                    // segmented again and what's been captured so far converted. If the target
+                    // can't be, nothing changes and the device is put back.
+                    match playlist.reload::<()>(rate, &segmentation) {
+                        Ok(()) => {
+                            model = None;
+                            trainer = None;
+                            sound = Sound::from_samples(resample(sound.samples(), sample_rate, rate), rate, None, None);
+                            output.sound = Sound::from_samples(resample(output.sound.samples(), sample_rate, rate), rate, None, None);
+                            for run in runs.iter_mut() {
+                                run.0 = (run.0 as f64 * rate / sample_rate) as usize;
+                            }
+                            library.resample(rate);
+                            // A recording can't change rate halfway through
+                            recorder = None;
+                            sample_rate = rate;
+                            show_target(&playlist, &library, &gui_prod, &status_prod);
+                        }
+                        Err(e) => {
+                            println!("could not reload target at {} Hz: {}", rate, e);
+                            gui_prod.send(GuiHandlerEvent::SampleRateRejected { rate: rate, previous: sample_rate });
+                        }
+                    }
+                }
+            }
+            Ok(SetSegmenter(FeatureSource::Capture, kind)) => {
+                segmentation.capture = kind;
+                gui_prod.send(GuiHandlerEvent::Segmenter(FeatureSource::Capture, kind));
+            }
+            Ok(SetSegmenter(FeatureSource::Target, kind)) => {
+                if kind != segmentation.target {
+                    let previous = segmentation.target;
+                    segmentation.target = kind;
+                    match playlist.reload::<()>(sample_rate, &segmentation) {
+                        Ok(()) => {
+                            model = None;
+                            trainer = None;
+                            show_target(&playlist, &library, &gui_prod, &status_prod);
+                        }
+                        Err(e) => {
+                            println!("could not segment target with {}: {}", kind.name(), e);
+                            segmentation.target = previous;
+                        }
+                    }
+                }
+                gui_prod.send(GuiHandlerEvent::Segmenter(FeatureSource::Target, segmentation.target));
+            }
+            Ok(SetGateLevel(level)) => {
+                segmentation.gate.level = level;
+                gui_prod.send(GuiHandlerEvent::GateLevel(level));
+            }
+            Ok(SetGrainLength(length)) => {
+                let previous = segmentation.grain.length;
+                segmentation.grain.length = length.max(0.001);
+                // A target cut into grains has to be cut again
+                if segmentation.target == SegmenterKind::Grain && segmentation.grain.length != previous {
+                    match playlist.reload::<()>(sample_rate, &segmentation) {
+                        Ok(()) => {
+                            model = None;
+                            trainer = None;
+                            show_target(&playlist, &library, &gui_prod, &status_prod);
+                        }
+                        Err(e) => {
+                            println!("could not cut target into {} s grains: {}", segmentation.grain.length, e);
+                            segmentation.grain.length = previous;
+                        }
+                    }
+                }
+                gui_prod.send(GuiHandlerEvent::GrainLength(segmentation.grain.length));
+            }
+            Ok(SwapInput(receiver)) => {
+                previous_input = input_buffer_receiver.take();
+                input_buffer_receiver = Some(receiver);
             }
             Ok(InputBuffer(buf)) => {
+                previous_input = None;
                 input_buffer_receiver = buf;
+                if input_buffer_receiver.is_some() {
+                    runs.push((sound.samples().len(), next_source.clone()));
+                }
+                recorder = None;
             }
             Ok(Quit) => { return; }
             Err(_) => { }
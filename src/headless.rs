@@ -0,0 +1,526 @@
+use portaudio::DeviceIndex;
+
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, TryRecvError};
+use std::{thread, time};
+
+use super::*;
+
+const HELP: &'static str = "\
+Commands:
+    start | stop        start or stop DSP
+    refresh             reconstruct the target from what's been captured
+    play | silence      start or stop playback of the reconstruction
+    loop                toggle looped playback
+    record              toggle recording the input to disk
+    export [PATH]       write the reconstruction to a WAV file
+    corpus save PATH [LABEL]
+                        save the capture dictionary, labelling its segments
+    corpus load PATH    add a saved corpus to the dictionaries reconstructed from
+    dict                list the dictionaries and what each supplied last time
+    dict on|off NAME    use a dictionary in the reconstruction or leave it out
+    dict weight NAME X  favour (above 1) or avoid (below 1) a dictionary
+    dict remove NAME    drop a dictionary from the library
+    provenance          show where each segment of the reconstruction came from
+    matcher NAME        match segments by euclidean or cosine distance between
+                        mean MFCCs, dtw over MFCC frames, or weighted with
+                        pitch and energy
+    selection NAME      choose each segment on its own (nearest) or the
+                        sequence that joins up best (viterbi)
+    targets             list the targets and whether they're ready
+    target N            reconstruct target N from the list instead
+    clear               throw away everything captured so far
+    threshold N         set the partitioner threshold
+    depth N             set the partitioner depth
+    segmenter SOURCE K  split the target or input (SOURCE) with the model,
+                        onset or grain segmenter (K)
+    grain MS            set the grain length in milliseconds
+    gate DB             drop captured segments that never reach DB dBFS
+    devices             list audio devices
+    rescan              look for devices plugged in or removed since startup
+    in N | out N        choose the input or output device
+    channels N... | all capture input channels N..., counting from 1, or all of
+                        them, mixed to mono
+    pan X               place the output between left (-1) and right (1)
+    level NAME X        set the input gain, monitor or reconstruction level
+                        (NAME is input, monitor or reconstruction)
+    rate HZ             set the device sample rate
+    buffer FRAMES       set the frames the device hands over at a time
+    latency [MS]        suggest a device latency in milliseconds, or default
+                        for the device's own; with none, show the measured
+                        round trip
+    queue BLOCKS        set how much input can wait for analysis, from 16 to
+                        262144 blocks
+    source PATH [SPEED] use a WAV file as the input, at SPEED times realtime
+                        (default 1, 0 for as fast as possible)
+    learn TARGET        map the next MIDI control moved to TARGET, an action
+                        name from the config file, threshold or depth
+    help                print this message
+    quit";
+
+/// A line of input to the headless frontend
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Perform(Action),
+    Export(PathBuf),
+    SaveCorpus(PathBuf, Option<String>),
+    LoadCorpus(PathBuf),
+    Dictionaries,
+    DictionaryEnabled(String, bool),
+    DictionaryWeight(String, f64),
+    RemoveDictionary(String),
+    Provenance,
+    Matcher(MatcherKind),
+    Selection(SelectionKind),
+    Targets,
+    Target(usize),
+    Threshold(usize),
+    Depth(usize),
+    Segmenter(FeatureSource, SegmenterKind),
+    /// In seconds
+    GrainLength(f64),
+    /// In dBFS
+    GateLevel(f64),
+    Devices,
+    Rescan,
+    InDevice(u32),
+    OutDevice(u32),
+    SampleRate(f64),
+    FramesPerBuffer(u32),
+    /// Suggested latency in seconds, `None` for the device default
+    Latency(Option<f64>),
+    RoundTrip,
+    QueueDepth(usize),
+    /// Input channels counting from 0, or `None` for all of them
+    InputChannels(Option<Vec<usize>>),
+    Pan(f32),
+    Level(Level, f32),
+    Source(PathBuf, f64),
+    Learn(String),
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Err(String::new()),
+        };
+
+        match command {
+            "start" => Ok(Command::Perform(Action::StartDsp)),
+            "stop" => Ok(Command::Perform(Action::StopDsp)),
+            "refresh" => Ok(Command::Perform(Action::Reconstruct)),
+            "silence" => Ok(Command::Perform(Action::StopPlayback)),
+            "loop" => Ok(Command::Perform(Action::ToggleLoop)),
+            "record" => Ok(Command::Perform(Action::ToggleRecord)),
+            "export" => match words.next() {
+                Some(path) => Ok(Command::Export(PathBuf::from(path))),
+                None => Ok(Command::Perform(Action::Export)),
+            },
+            "corpus" => match (words.next(), words.next()) {
+                (Some("save"), Some(path)) => Ok(Command::SaveCorpus(PathBuf::from(path), words.next().map(|l| l.to_string()))),
+                (Some("load"), Some(path)) => Ok(Command::LoadCorpus(PathBuf::from(path))),
+                _ => Err("corpus needs save PATH [LABEL] or load PATH".to_string()),
+            },
+            "dict" => match (words.next(), words.next()) {
+                (None, _) => Ok(Command::Dictionaries),
+                (Some("on"), Some(name)) => Ok(Command::DictionaryEnabled(name.to_string(), true)),
+                (Some("off"), Some(name)) => Ok(Command::DictionaryEnabled(name.to_string(), false)),
+                (Some("remove"), Some(name)) => Ok(Command::RemoveDictionary(name.to_string())),
+                (Some("weight"), Some(name)) => {
+                    let weight = try!(words.next().ok_or("dict weight needs a weight".to_string()));
+                    match weight.parse::<f64>() {
+                        Ok(x) if x > 0. => Ok(Command::DictionaryWeight(name.to_string(), x)),
+                        _ => Err(format!("{} is not a weight above 0", weight)),
+                    }
+                }
+                _ => Err("dict needs on, off or remove NAME, or weight NAME X".to_string()),
+            },
+            "provenance" => Ok(Command::Provenance),
+            "matcher" => {
+                let names = MATCHERS.iter().map(|k| k.name()).collect::<Vec<&str>>().join(", ");
+                words.next().and_then(MatcherKind::from_name)
+                    .map(Command::Matcher)
+                    .ok_or(format!("matcher needs one of: {}", names))
+            }
+            "selection" => {
+                let names = SELECTIONS.iter().map(|k| k.name()).collect::<Vec<&str>>().join(", ");
+                words.next().and_then(SelectionKind::from_name)
+                    .map(Command::Selection)
+                    .ok_or(format!("selection needs one of: {}", names))
+            }
+            "targets" => Ok(Command::Targets),
+            "target" => number(command, &mut words).map(Command::Target),
+            "threshold" => number(command, &mut words).map(Command::Threshold),
+            "depth" => number(command, &mut words).map(Command::Depth),
+            "segmenter" => {
+                let source = match words.next() {
+                    Some("target") => FeatureSource::Target,
+                    Some("input") => FeatureSource::Capture,
+                    _ => return Err("segmenter needs target or input".to_string()),
+                };
+                let names = SEGMENTERS.iter().map(|k| k.name()).collect::<Vec<&str>>().join(", ");
+                words.next().and_then(SegmenterKind::from_name)
+                    .map(|kind| Command::Segmenter(source, kind))
+                    .ok_or(format!("segmenter needs one of: {}", names))
+            }
+            "grain" => number(command, &mut words)
+                .and_then(|ms| if ms > 0 { Ok(Command::GrainLength(ms as f64 / 1000.)) } else { Err("grains can't be empty".to_string()) }),
+            "gate" => {
+                let level = try!(words.next().ok_or("gate needs a level in dBFS".to_string()));
+                match level.parse::<f64>() {
+                    Ok(x) if x <= 0. => Ok(Command::GateLevel(x)),
+                    _ => Err(format!("{} is not a level in dBFS", level)),
+                }
+            }
+            "devices" => Ok(Command::Devices),
+            "rescan" => Ok(Command::Rescan),
+            "in" => number(command, &mut words).map(|n| Command::InDevice(n as u32)),
+            "out" => number(command, &mut words).map(|n| Command::OutDevice(n as u32)),
+            "rate" => number(command, &mut words).map(|n| Command::SampleRate(n as f64)),
+            "buffer" => number(command, &mut words).map(|n| Command::FramesPerBuffer(n as u32)),
+            "latency" => match words.next() {
+                None => Ok(Command::RoundTrip),
+                Some("default") => Ok(Command::Latency(None)),
+                Some(ms) => match ms.parse::<f64>() {
+                    Ok(x) if x > 0. => Ok(Command::Latency(Some(x / 1000.))),
+                    _ => Err(format!("{} is not a latency in milliseconds", ms)),
+                },
+            },
+            "queue" => number(command, &mut words).map(Command::QueueDepth),
+            "channels" => {
+                let words: Vec<&str> = words.collect();
+                if words == ["all"] {
+                    return Ok(Command::InputChannels(None));
+                }
+                let channels = try!(words.iter().map(|w| match w.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(n - 1),
+                    _ => Err(format!("{} is not a channel number", w)),
+                }).collect::<Result<Vec<usize>, String>>());
+                if channels.is_empty() {
+                    Err("channels needs channel numbers or all".to_string())
+                } else {
+                    Ok(Command::InputChannels(Some(channels)))
+                }
+            }
+            "pan" => {
+                let pan = try!(words.next().ok_or("pan needs a position".to_string()));
+                match pan.parse::<f32>() {
+                    Ok(x) if x >= -1. && x <= 1. => Ok(Command::Pan(x)),
+                    _ => Err(format!("pan should be between -1 and 1, not {}", pan)),
+                }
+            }
+            "level" => {
+                let names = LEVELS.iter().map(|l| l.name()).collect::<Vec<&str>>().join(", ");
+                let level = try!(words.next().and_then(Level::from_name)
+                                 .ok_or(format!("level needs one of: {}", names)));
+                let value = try!(words.next().ok_or("level needs a value".to_string()));
+                match value.parse::<f32>() {
+                    Ok(x) if x >= 0. => Ok(Command::Level(level, x)),
+                    _ => Err(format!("{} is not a level", value)),
+                }
+            }
+            "source" => {
+                let path = try!(words.next().ok_or("source needs a path".to_string()));
+                let speed = match words.next() {
+                    Some(speed) => try!(speed.parse::<f64>().map_err(|_| format!("{} is not a number", speed))),
+                    None => 1.,
+                };
+                Ok(Command::Source(PathBuf::from(path), speed))
+            }
+            "learn" => words.next()
+                .map(|target| Command::Learn(target.to_string()))
+                .ok_or(format!("learn needs one of: {}", learnable_targets().join(", "))),
+            "help" => Ok(Command::Help),
+            "quit" | "exit" => Ok(Command::Quit),
+            // Anything else can be named the way it is in the config file
+            _ => Action::from_name(command)
+                .map(Command::Perform)
+                .ok_or(format!("unknown command {}, try help", command)),
+        }
+    }
+}
+
+fn number<'a, I: Iterator<Item=&'a str>>(command: &str, words: &mut I) -> Result<usize, String> {
+    words.next()
+        .ok_or(format!("{} needs a number", command))
+        .and_then(|n| n.parse::<usize>().map_err(|_| format!("{} is not a number", n)))
+}
+
+/// Frontend for running without a display. Drives the handlers from commands typed (or piped)
+/// on stdin and prints what the GUI would otherwise show.
+pub fn headless_handler<T>(mut controller: Controller, gui_recv: mpsc::Receiver<GuiHandlerEvent>, remote_recv: mpsc::Receiver<Remote>) -> Result<(), Error<T>> {
+    // Reading stdin blocks, so it gets a thread of its own. It's not scoped: it can't be woken
+    // up to exit, and will go away with the process.
+    let (line_prod, line_recv) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => if line_prod.send(line).is_err() { break; },
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut devices: Vec<DeviceEntry> = Vec::new();
+    let mut in_device = None;
+    let mut out_device = None;
+    let mut input_channels = 1;
+    let mut round_trip = None;
+    let mut dictionaries: Vec<DictionaryInfo> = Vec::new();
+    let mut provenance: Vec<Provenance> = Vec::new();
+    let mut targets: Vec<TargetInfo> = Vec::new();
+    let mut current_target = 0;
+    let mut stdin_open = true;
+
+    println!("{}", HELP);
+
+    loop {
+        while let Ok(handler_event) = gui_recv.try_recv() {
+            match handler_event {
+                GuiHandlerEvent::Devices(d) => devices = d,
+                GuiHandlerEvent::InDevice(d) => in_device = Some(d),
+                GuiHandlerEvent::OutDevice(d) => out_device = Some(d),
+                GuiHandlerEvent::SampleRate(rate) => println!("sample rate is {} Hz", rate),
+                GuiHandlerEvent::SampleRateRejected { rate, previous } => {
+                    println!("cannot analyse at {} Hz, going back to {} Hz", rate, previous);
+                    try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetSampleRate(previous))));
+                }
+                GuiHandlerEvent::InputChannels(n) => input_channels = n,
+                GuiHandlerEvent::Stream(Some(info)) => {
+                    in_device = Some(info.in_device.0 as usize);
+                    out_device = Some(info.out_device.0 as usize);
+                    println!("stream open on devices {} and {} at {} Hz, latency {:.1} ms in, {:.1} ms out",
+                             info.in_device.0, info.out_device.0, info.sample_rate,
+                             info.input_latency * 1000., info.output_latency * 1000.);
+                }
+                GuiHandlerEvent::Stream(None) => {
+                    println!("stream closed");
+                    round_trip = None;
+                }
+                GuiHandlerEvent::Buffer { frames_per_buffer, latency, queue_depth } => {
+                    let latency = match latency {
+                        Some(latency) => format!("{:.1} ms", latency * 1000.),
+                        None => "device default".to_string(),
+                    };
+                    println!("buffer is {} frames, latency {}, queue {} blocks", frames_per_buffer, latency, queue_depth);
+                }
+                GuiHandlerEvent::RoundTrip(r) => round_trip = r,
+                GuiHandlerEvent::Segmenter(source, kind) => {
+                    let source = if source == FeatureSource::Target { "target" } else { "input" };
+                    println!("splitting {} with the {} segmenter", source, kind.name());
+                }
+                GuiHandlerEvent::GrainLength(length) => println!("grains are {} ms", length * 1000.),
+                GuiHandlerEvent::GateLevel(level) => println!("gate is at {} dBFS", level),
+                GuiHandlerEvent::Dictionaries(d) => dictionaries = d,
+                GuiHandlerEvent::Provenance(p) => provenance = p,
+                GuiHandlerEvent::Matcher(kind) => println!("matching segments by {}", kind.name()),
+                GuiHandlerEvent::Selection(kind) => println!("choosing segments by {}", kind.name()),
+                GuiHandlerEvent::Targets { targets: t, current } => {
+                    if current != current_target || targets.is_empty() {
+                        println!("reconstructing target {}", t.get(current).map(|t| t.name.as_str()).unwrap_or("?"));
+                    }
+                    targets = t;
+                    current_target = current;
+                }
+                // Already reported by the dictionary handler
+                GuiHandlerEvent::Gated(..) | GuiHandlerEvent::Features(..) => { }
+            }
+        }
+
+        while let Ok(remote) = remote_recv.try_recv() {
+            try!(controller.remote(remote));
+        }
+
+        let line = match line_recv.try_recv() {
+            Ok(line) => line,
+            Err(TryRecvError::Empty) => {
+                thread::sleep(time::Duration::from_millis(10));
+                continue;
+            }
+            Err(TryRecvError::Disconnected) => {
+                // Keep running without a console, e.g. when started with stdin closed
+                if stdin_open {
+                    println!("stdin closed, no longer reading commands");
+                    stdin_open = false;
+                }
+                thread::sleep(time::Duration::from_millis(10));
+                continue;
+            }
+        };
+
+        match Command::parse(&line) {
+            Ok(Command::Perform(action)) => try!(controller.perform(action)),
+            Ok(Command::Export(path)) => try!(controller.dictionary(DictionaryHandlerEvent::Export(path))),
+            Ok(Command::SaveCorpus(path, label)) => try!(controller.dictionary(DictionaryHandlerEvent::SaveCorpus(path, label))),
+            Ok(Command::LoadCorpus(path)) => try!(controller.dictionary(DictionaryHandlerEvent::LoadCorpus(path))),
+            Ok(Command::Dictionaries) => {
+                if dictionaries.is_empty() {
+                    println!("no dictionaries yet, refresh or load a corpus");
+                }
+                for info in dictionaries.iter() {
+                    println!("{:<16} {:>5} segments, weight {:.2}, {} used{}", info.name, info.segments,
+                             info.weight, info.used, if info.enabled { "" } else { " [off]" });
+                }
+            }
+            Ok(Command::DictionaryEnabled(name, enabled)) => {
+                try!(controller.dictionary(DictionaryHandlerEvent::SetDictionaryEnabled(name, enabled)));
+            }
+            Ok(Command::DictionaryWeight(name, weight)) => {
+                try!(controller.dictionary(DictionaryHandlerEvent::SetDictionaryWeight(name, weight)));
+            }
+            Ok(Command::RemoveDictionary(name)) => {
+                try!(controller.dictionary(DictionaryHandlerEvent::RemoveDictionary(name)));
+            }
+            Ok(Command::Provenance) => {
+                for p in provenance.iter() {
+                    let source = p.source.as_ref().map(|s| s.as_str()).unwrap_or("live");
+                    let target = p.target_label.as_ref().map(|l| format!("{} [{}]", p.target, l)).unwrap_or(p.target.to_string());
+                    println!("{:>4}: {} #{} from {} {:.3}-{:.3} s{}", target, p.dictionary, p.segment, source,
+                             p.start, p.end, p.label.as_ref().map(|l| format!(" [{}]", l)).unwrap_or(String::new()));
+                }
+            }
+            Ok(Command::Matcher(kind)) => try!(controller.dictionary(DictionaryHandlerEvent::SetMatcher(kind))),
+            Ok(Command::Selection(kind)) => try!(controller.dictionary(DictionaryHandlerEvent::SetSelection(kind))),
+            Ok(Command::Targets) => {
+                for (idx, target) in targets.iter().enumerate() {
+                    let mut marks = String::new();
+                    if idx == current_target { marks.push_str(" [current]"); }
+                    if !target.ready { marks.push_str(" [loading]"); }
+                    println!("{:>3}: {}{}", idx, target.name, marks);
+                }
+            }
+            Ok(Command::Target(idx)) => try!(controller.dictionary(DictionaryHandlerEvent::SetTarget(idx))),
+            Ok(Command::Threshold(x)) => try!(controller.set_threshold(x)),
+            Ok(Command::Depth(x)) => try!(controller.set_depth(x)),
+            Ok(Command::Segmenter(source, kind)) => {
+                try!(controller.dictionary(DictionaryHandlerEvent::SetSegmenter(source, kind)));
+            }
+            Ok(Command::GrainLength(length)) => {
+                try!(controller.dictionary(DictionaryHandlerEvent::SetGrainLength(length)));
+            }
+            Ok(Command::GateLevel(level)) => {
+                try!(controller.dictionary(DictionaryHandlerEvent::SetGateLevel(level)));
+            }
+            Ok(Command::Devices) => {
+                for device in devices.iter() {
+                    let DeviceIndex(idx) = device.index;
+                    let mut marks = String::new();
+                    if in_device == Some(idx as usize) { marks.push_str(" [in]"); }
+                    if out_device == Some(idx as usize) { marks.push_str(" [out]"); }
+                    println!("{:>3}: {}{}", idx, device.label(), marks);
+                }
+            }
+            Ok(Command::Rescan) => try!(controller.audio(AudioHandlerEvent::Rescan)),
+            Ok(Command::InDevice(idx)) => {
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetInDevice(idx))));
+                in_device = Some(idx as usize);
+            }
+            Ok(Command::OutDevice(idx)) => {
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetOutDevice(idx))));
+                out_device = Some(idx as usize);
+            }
+            Ok(Command::SampleRate(rate)) => {
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetSampleRate(rate))));
+            }
+            Ok(Command::FramesPerBuffer(frames)) => {
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetFramesPerBuffer(frames))));
+            }
+            Ok(Command::Latency(latency)) => {
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetLatency(latency))));
+            }
+            Ok(Command::RoundTrip) => match round_trip {
+                Some(r) => println!("round trip is {:.1} ms", r * 1000.),
+                None => println!("round trip is unknown until DSP is running"),
+            },
+            Ok(Command::QueueDepth(depth)) => {
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetQueueDepth(depth))));
+            }
+            Ok(Command::InputChannels(channels)) => {
+                let channels = channels.unwrap_or((0..input_channels).collect());
+                try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetInputChannels(channels))));
+            }
+            Ok(Command::Pan(pan)) => try!(controller.audio(AudioHandlerEvent::Setting(DeviceSetting::SetPan(pan)))),
+            Ok(Command::Level(level, value)) => try!(controller.audio(AudioHandlerEvent::SetLevel(level, value))),
+            Ok(Command::Source(path, speed)) => try!(controller.load_source(path, speed)),
+            Ok(Command::Learn(target)) => {
+                if let Err(e) = controller.learn_midi::<T>(&target) {
+                    println!("{}", e);
+                }
+            }
+            Ok(Command::Help) => println!("{}", HELP),
+            Ok(Command::Quit) => break,
+            Err(ref e) if e.is_empty() => { }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    controller.quit();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_actions_and_their_config_names() {
+        assert_eq!(Command::parse("start"), Ok(Command::Perform(Action::StartDsp)));
+        assert_eq!(Command::parse("  refresh  "), Ok(Command::Perform(Action::Reconstruct)));
+        assert_eq!(Command::parse("threshold_up"), Ok(Command::Perform(Action::ThresholdUp)));
+        assert_eq!(Command::parse("exit"), Ok(Command::Quit));
+        assert!(Command::parse("frobnicate").is_err());
+        assert_eq!(Command::parse(""), Err(String::new()));
+    }
+
+    #[test]
+    fn parses_numbers_and_units() {
+        assert_eq!(Command::parse("threshold 7"), Ok(Command::Threshold(7)));
+        assert_eq!(Command::parse("grain 50"), Ok(Command::GrainLength(0.05)));
+        assert_eq!(Command::parse("latency 10"), Ok(Command::Latency(Some(0.01))));
+        assert_eq!(Command::parse("latency default"), Ok(Command::Latency(None)));
+        assert_eq!(Command::parse("latency"), Ok(Command::RoundTrip));
+        assert_eq!(Command::parse("gate -40"), Ok(Command::GateLevel(-40.)));
+        assert!(Command::parse("threshold").is_err());
+        assert!(Command::parse("depth -1").is_err());
+        assert!(Command::parse("grain 0").is_err());
+        assert!(Command::parse("gate 3").is_err());
+    }
+
+    #[test]
+    fn parses_channels_from_one() {
+        assert_eq!(Command::parse("channels 1 3"), Ok(Command::InputChannels(Some(vec![0, 2]))));
+        assert_eq!(Command::parse("channels all"), Ok(Command::InputChannels(None)));
+        assert!(Command::parse("channels 0").is_err());
+        assert!(Command::parse("channels").is_err());
+    }
+
+    #[test]
+    fn parses_mix_and_source() {
+        assert_eq!(Command::parse("pan -0.5"), Ok(Command::Pan(-0.5)));
+        assert!(Command::parse("pan 2").is_err());
+        assert_eq!(Command::parse("level monitor 0.5"), Ok(Command::Level(Level::Monitor, 0.5)));
+        assert_eq!(Command::parse("source in.wav"), Ok(Command::Source(PathBuf::from("in.wav"), 1.)));
+        assert_eq!(Command::parse("source in.wav 0"), Ok(Command::Source(PathBuf::from("in.wav"), 0.)));
+    }
+
+    #[test]
+    fn parses_library_commands() {
+        assert_eq!(Command::parse("dict"), Ok(Command::Dictionaries));
+        assert_eq!(Command::parse("dict off capture"), Ok(Command::DictionaryEnabled("capture".to_string(), false)));
+        assert_eq!(Command::parse("dict weight voice 2"), Ok(Command::DictionaryWeight("voice".to_string(), 2.)));
+        assert!(Command::parse("dict weight voice 0").is_err());
+        assert_eq!(Command::parse("corpus save a.corpus take"),
+                   Ok(Command::SaveCorpus(PathBuf::from("a.corpus"), Some("take".to_string()))));
+        assert_eq!(Command::parse("matcher dtw"), Ok(Command::Matcher(MatcherKind::Dtw)));
+        assert_eq!(Command::parse("selection viterbi"), Ok(Command::Selection(SelectionKind::Viterbi)));
+        assert!(Command::parse("matcher nearest").is_err());
+        assert_eq!(Command::parse("segmenter input grain"), Ok(Command::Segmenter(FeatureSource::Capture, SegmenterKind::Grain)));
+        assert_eq!(Command::parse("target 2"), Ok(Command::Target(2)));
+    }
+}
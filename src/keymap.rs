@@ -0,0 +1,159 @@
+use conrod::input::Key;
+
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// Keyboard shortcuts for the GUI, one key per action
+pub struct Keymap {
+    bindings: Vec<(Key, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        use Action::*;
+        Keymap {
+            bindings: vec![
+                (Key::D, StartDsp),
+                (Key::X, StopDsp),
+                (Key::Space, Reconstruct),
+                (Key::P, Play),
+                (Key::S, StopPlayback),
+                (Key::E, Export),
+                (Key::C, Clear),
+                (Key::Up, ThresholdUp),
+                (Key::Down, ThresholdDown),
+                (Key::Right, DepthUp),
+                (Key::Left, DepthDown),
+                (Key::L, ToggleLoop),
+                (Key::R, ToggleRecord),
+                (Key::H, ToggleHelp),
+            ]
+        }
+    }
+}
+
+impl Keymap {
+    /// Starts from the default bindings and rebinds every action named in the `[keys]` table of
+    /// the config file, e.g. `play = "Return"`.
+    pub fn from_config<T>(keys: &BTreeMap<String, String>) -> Result<Keymap, Error<T>> {
+        let mut keymap = Keymap::default();
+        for (action_name, key_name) in keys.iter() {
+            let action = try!(Action::from_name(action_name)
+                              .ok_or(Error::String(format!("unknown action \"{}\" in [keys]", action_name))));
+            let key = try!(key_from_name(key_name)
+                           .ok_or(Error::String(format!("unknown key \"{}\" for {}", key_name, action_name))));
+            keymap.bind(key, action);
+        }
+        Ok(keymap)
+    }
+
+    /// Binds the key to the action, replacing any previous binding of either
+    pub fn bind(&mut self, key: Key, action: Action) {
+        self.bindings.retain(|&(k, a)| k != key && a != action);
+        self.bindings.push((key, action));
+    }
+
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.bindings.iter().find(|&&(k, _)| k == key).map(|&(_, a)| a)
+    }
+
+    /// One line per binding, in the order of `ACTIONS`
+    pub fn help_text(&self) -> String {
+        ACTIONS.iter().filter_map(|action| {
+            self.bindings.iter().find(|&&(_, a)| a == *action)
+                .map(|&(key, _)| format!("{:<10} {}", format!("{:?}", key), action.description()))
+        }).collect::<Vec<String>>().join("\n")
+    }
+}
+
+/// Parses the names used in the config file. Letters, digits and function keys are named as
+/// written ("P", "5", "F1"), everything else by its `Key` variant name ("Space", "Return").
+pub fn key_from_name(name: &str) -> Option<Key> {
+    let key = match name.to_uppercase().as_str() {
+        "A" => Key::A, "B" => Key::B, "C" => Key::C, "D" => Key::D, "E" => Key::E,
+        "F" => Key::F, "G" => Key::G, "H" => Key::H, "I" => Key::I, "J" => Key::J,
+        "K" => Key::K, "L" => Key::L, "M" => Key::M, "N" => Key::N, "O" => Key::O,
+        "P" => Key::P, "Q" => Key::Q, "R" => Key::R, "S" => Key::S, "T" => Key::T,
+        "U" => Key::U, "V" => Key::V, "W" => Key::W, "X" => Key::X, "Y" => Key::Y,
+        "Z" => Key::Z,
+        "0" => Key::D0, "1" => Key::D1, "2" => Key::D2, "3" => Key::D3, "4" => Key::D4,
+        "5" => Key::D5, "6" => Key::D6, "7" => Key::D7, "8" => Key::D8, "9" => Key::D9,
+        "F1" => Key::F1, "F2" => Key::F2, "F3" => Key::F3, "F4" => Key::F4,
+        "F5" => Key::F5, "F6" => Key::F6, "F7" => Key::F7, "F8" => Key::F8,
+        "F9" => Key::F9, "F10" => Key::F10, "F11" => Key::F11, "F12" => Key::F12,
+        "SPACE" => Key::Space,
+        "RETURN" | "ENTER" => Key::Return,
+        "TAB" => Key::Tab,
+        "BACKSPACE" => Key::Backspace,
+        "UP" => Key::Up,
+        "DOWN" => Key::Down,
+        "LEFT" => Key::Left,
+        "RIGHT" => Key::Right,
+        "MINUS" => Key::Minus,
+        "EQUALS" => Key::Equals,
+        "COMMA" => Key::Comma,
+        "PERIOD" => Key::Period,
+        "SLASH" => Key::Slash,
+        "LEFTBRACKET" => Key::LeftBracket,
+        "RIGHTBRACKET" => Key::RightBracket,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|&(a, k)| (a.to_string(), k.to_string())).collect()
+    }
+
+    #[test]
+    fn bind_replaces_the_key_and_the_action() {
+        let mut keymap = Keymap::default();
+        keymap.bind(Key::D, Action::Play);
+        assert_eq!(keymap.action(Key::D), Some(Action::Play));
+        assert_eq!(keymap.action(Key::P), None);
+        assert!(!keymap.help_text().contains(Action::StartDsp.description()));
+        assert_eq!(keymap.help_text().lines().count(), ACTIONS.len() - 1);
+    }
+
+    #[test]
+    fn config_rebinds_named_actions() {
+        let keymap = Keymap::from_config::<()>(&keys(&[("play", "return"), ("clear", "f5")])).unwrap();
+        assert_eq!(keymap.action(Key::Return), Some(Action::Play));
+        assert_eq!(keymap.action(Key::F5), Some(Action::Clear));
+        assert_eq!(keymap.action(Key::C), None);
+        assert_eq!(keymap.action(Key::Space), Some(Action::Reconstruct));
+    }
+
+    #[test]
+    fn config_rejects_unknown_names() {
+        assert!(Keymap::from_config::<()>(&keys(&[("dance", "P")])).is_err());
+        assert!(Keymap::from_config::<()>(&keys(&[("play", "Hyper")])).is_err());
+    }
+
+    #[test]
+    fn key_names_ignore_case() {
+        assert_eq!(key_from_name("return"), Some(Key::Return));
+        assert_eq!(key_from_name("ENTER"), Some(Key::Return));
+        assert_eq!(key_from_name("p"), Some(Key::P));
+        assert_eq!(key_from_name("5"), Some(Key::D5));
+        assert_eq!(key_from_name("PageUp"), None);
+    }
+
+    #[test]
+    fn help_follows_the_order_of_actions() {
+        let mut keymap = Keymap::default();
+        // Rebinding moves the binding to the end of the list, but not of the help
+        keymap.bind(Key::Return, Action::StartDsp);
+        let lines: Vec<String> = keymap.help_text().lines().map(|l| l.to_string()).collect();
+        assert_eq!(lines.len(), ACTIONS.len());
+        for (line, action) in lines.iter().zip(ACTIONS.iter()) {
+            assert!(line.ends_with(action.description()), "{} out of order", line);
+        }
+        assert!(lines[0].starts_with("Return"));
+    }
+}
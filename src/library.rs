@@ -0,0 +1,300 @@
+use soundsym::*;
+use toml;
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use super::*;
+
+/// Name the live capture goes by in the library
+pub const CAPTURE_NAME: &'static str = "capture";
+
+/// A dictionary the target can be reconstructed from
+pub struct LibraryEntry {
+    pub name: String,
+    pub corpus: Corpus,
+    pub enabled: bool,
+    /// Above 1 its segments are preferred over closer ones from elsewhere, below 1 avoided
+    pub weight: f64,
+    features: Vec<SegmentFeatures>,
+}
+
+/// What the frontend shows of each dictionary
+#[derive(Debug, Clone)]
+pub struct DictionaryInfo {
+    pub name: String,
+    pub segments: usize,
+    pub enabled: bool,
+    pub weight: f64,
+    /// Segments of the latest reconstruction taken from it
+    pub used: usize,
+}
+
+/// Where one segment of a reconstruction came from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Segment of the target it stands in for
+    pub target: usize,
+    /// Label of the target segment, if the target has a label file
+    pub target_label: Option<String>,
+    pub dictionary: String,
+    /// Segment within the dictionary
+    pub segment: usize,
+    pub source: Option<String>,
+    /// Seconds from the start of the capture it was cut from
+    pub start: f64,
+    pub end: f64,
+    pub label: Option<String>,
+    /// Weighted distance to the target segment
+    pub distance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ProvenanceFile {
+    segments: Vec<Provenance>,
+}
+
+/// The reconstructed audio along with where each piece of it came from
+pub struct Reconstruction {
+    pub samples: Vec<f64>,
+    pub provenance: Vec<Provenance>,
+}
+
+/// Name for a corpus loaded from `path`: the file name, unless that's the capture's, which
+/// would have it replaced by the next refresh
+pub fn corpus_name(path: &Path) -> String {
+    let name = path.file_stem().map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string());
+    if name == CAPTURE_NAME { format!("{}-loaded", name) } else { name }
+}
+
+/// Writes provenance out as TOML, one `[[segments]]` table per segment
+pub fn save_provenance<T>(provenance: &[Provenance], path: &Path) -> Result<(), Error<T>> {
+    let file = ProvenanceFile { segments: provenance.to_vec() };
+    let contents = try!(toml::to_string(&file)
+                        .map_err(|e| Error::String(format!("cannot serialize provenance: {}", e))));
+    let mut file = try!(File::create(path));
+    try!(file.write_all(contents.as_bytes()));
+    Ok(())
+}
+
+/// The set of named dictionaries the target is reconstructed from: the live capture and any
+/// corpora loaded from disk. Each can be switched off or weighted, and a reconstruction draws
+/// on the union of the ones that are on.
+pub struct Library {
+    entries: Vec<LibraryEntry>,
+    used: Vec<usize>,
+}
+
+impl Library {
+    pub fn new() -> Library {
+        Library { entries: Vec::new(), used: Vec::new() }
+    }
+
+    /// Adds a dictionary, or replaces the one with the same name, keeping its settings
+    pub fn insert(&mut self, name: &str, corpus: Corpus) {
+        let features = segment_features(&corpus);
+        match self.entries.iter().position(|e| e.name == name) {
+            Some(idx) => {
+                self.entries[idx].corpus = corpus;
+                self.entries[idx].features = features;
+            }
+            None => {
+                self.entries.push(LibraryEntry {
+                    name: name.to_string(),
+                    corpus: corpus,
+                    enabled: true,
+                    weight: 1.,
+                    features: features,
+                });
+            }
+        }
+        self.used = vec![0; self.entries.len()];
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        let idx = try!(self.position(name));
+        self.entries.remove(idx);
+        self.used = vec![0; self.entries.len()];
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LibraryEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let idx = try!(self.position(name));
+        self.entries[idx].enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_weight(&mut self, name: &str, weight: f64) -> Result<(), String> {
+        if weight <= 0. {
+            return Err("weights have to be above 0".to_string());
+        }
+        let idx = try!(self.position(name));
+        self.entries[idx].weight = weight;
+        Ok(())
+    }
+
+    /// Converts every dictionary to another rate
+    pub fn resample(&mut self, sample_rate: f64) {
+        for entry in self.entries.iter_mut() {
+            entry.corpus.resample(sample_rate);
+            entry.features = segment_features(&entry.corpus);
+        }
+    }
+
+    pub fn info(&self) -> Vec<DictionaryInfo> {
+        self.entries.iter().zip(self.used.iter()).map(|(e, used)| DictionaryInfo {
+            name: e.name.clone(),
+            segments: e.corpus.segments.len(),
+            enabled: e.enabled,
+            weight: e.weight,
+            used: *used,
+        }).collect()
+    }
+
+    /// Segments in the dictionaries that are switched on
+    pub fn enabled_segments(&self) -> usize {
+        self.entries.iter().filter(|e| e.enabled).map(|e| e.corpus.segments.len()).sum()
+    }
+
+    /// Stands an enabled segment in for each target segment, chosen as `selection` says.
+    /// Distances are divided by the weight of the segment's dictionary. The segments are laid
+    /// end to end, or windowed and overlap-added `hop` samples apart if the target's segments
+    /// overlap.
+    pub fn reconstruct(&mut self, target: &[SegmentFeatures], hop: Option<usize>, matcher: &Matcher, selection: &SelectionConfig) -> Result<Reconstruction, String> {
+        if self.enabled_segments() == 0 {
+            return Err("no segments to reconstruct from".to_string());
+        }
+
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        let mut provenance = Vec::with_capacity(target.len());
+        self.used = vec![0; self.entries.len()];
+
+        let chosen = {
+            let units: Vec<Candidate> = self.entries.iter().enumerate()
+                .filter(|&(_, e)| e.enabled)
+                .flat_map(|(d, e)| e.features.iter().enumerate().map(move |(s, features)| Candidate {
+                    dictionary: d,
+                    segment: s,
+                    features: features,
+                    weight: e.weight,
+                }))
+                .collect();
+            select_units(target, &units[..], matcher, selection).into_iter()
+                .map(|(u, distance)| (units[u].dictionary, units[u].segment, distance))
+                .collect::<Vec<_>>()
+        };
+
+        for (t, (d, s, distance)) in chosen.into_iter().enumerate() {
+            let entry = &self.entries[d];
+            let segment = &entry.corpus.segments[s];
+            match hop {
+                Some(hop) => overlap_add(&mut samples, &mut weights, t * hop, &segment.samples[..]),
+                None => samples.extend_from_slice(&segment.samples[..]),
+            }
+            provenance.push(Provenance {
+                target: t,
+                target_label: None,
+                dictionary: entry.name.clone(),
+                segment: s,
+                source: segment.source.clone(),
+                start: segment.start,
+                end: segment.end,
+                label: segment.label.clone(),
+                distance: distance,
+            });
+            self.used[d] += 1;
+        }
+        normalise_overlap(&mut samples[..], &weights[..]);
+
+        Ok(Reconstruction { samples: samples, provenance: provenance })
+    }
+
+    fn position(&self, name: &str) -> Result<usize, String> {
+        self.entries.iter().position(|e| e.name == name)
+            .ok_or(format!("no dictionary called {}", name))
+    }
+}
+
+/// Adds `grain` into `samples` from `start` under a Hann window, keeping the total window
+/// at each sample in `weights`
+fn overlap_add(samples: &mut Vec<f64>, weights: &mut Vec<f64>, start: usize, grain: &[f64]) {
+    let end = start + grain.len();
+    if samples.len() < end {
+        samples.resize(end, 0.);
+        weights.resize(end, 0.);
+    }
+    let length = grain.len() as f64;
+    for (i, x) in grain.iter().enumerate() {
+        let w = 0.5 - 0.5 * (2. * PI * i as f64 / length).cos();
+        samples[start + i] += w * x;
+        weights[start + i] += w;
+    }
+}
+
+/// Divides overlap-added samples by the window total, so the result is the same level however
+/// much the grains overlap and however long they are
+fn normalise_overlap(samples: &mut [f64], weights: &[f64]) {
+    for (s, &w) in samples.iter_mut().zip(weights.iter()) {
+        if w > 1e-6 {
+            *s /= w;
+        }
+    }
+}
+
+/// Features of every segment, working the MFCCs out again where the corpus has none
+fn segment_features(corpus: &Corpus) -> Vec<SegmentFeatures> {
+    corpus.segments.iter().map(|segment| {
+        let mfccs = if segment.mfccs.is_empty() {
+            Sound::from_samples(segment.samples.clone(), corpus.sample_rate, None, None).mfccs().clone()
+        } else {
+            segment.mfccs.clone()
+        };
+        SegmentFeatures::new(&segment.samples[..], mfccs, corpus.sample_rate)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_grains_keep_their_level() {
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        for n in 0..5 {
+            overlap_add(&mut samples, &mut weights, n * 4, &[2.; 8]);
+        }
+        assert_eq!(samples.len(), 24);
+        normalise_overlap(&mut samples, &weights);
+        // The very first sample is where the window is zero, so there's nothing to scale
+        assert_eq!(samples[0], 0.);
+        for s in samples[1..].iter() {
+            assert!((s - 2.).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn overlap_add_grows_the_output() {
+        let mut samples = vec![1.; 4];
+        let mut weights = vec![1.; 4];
+        overlap_add(&mut samples, &mut weights, 2, &[1.; 4]);
+        assert_eq!(samples.len(), 6);
+        assert_eq!(weights.len(), 6);
+        assert_eq!(samples[0], 1.);
+        assert_eq!(samples[2], 1.);
+    }
+
+    #[test]
+    fn loaded_corpora_keep_clear_of_the_capture() {
+        assert_eq!(corpus_name(Path::new("voices/choir.corpus")), "choir");
+        assert_eq!(corpus_name(Path::new("capture.corpus")), "capture-loaded");
+    }
+}
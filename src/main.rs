@@ -7,13 +7,24 @@ extern crate bounded_spsc_queue;
 extern crate find_folder;
 extern crate rusty_machine;
 extern crate piston_window;
+extern crate serde;
+extern crate toml;
+extern crate rosc;
+extern crate midir;
+extern crate chrono;
+extern crate rustfft;
+extern crate bincode;
+
+#[macro_use] extern crate serde_derive;
 
 #[macro_use] extern crate conrod;
 
 use crossbeam::sync::SegQueue;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::cell::RefCell;
+use std::env;
+use std::path::PathBuf;
 use std::sync::mpsc;
 
 mod error;
@@ -22,26 +33,213 @@ pub use error::Error;
 mod events;
 pub use events::*;
 
+mod config;
+pub use config::*;
+
+mod actions;
+pub use actions::*;
+
+mod keymap;
+pub use keymap::*;
+
+mod options;
+pub use options::*;
+
+mod headless;
+pub use headless::*;
+
+mod osc;
+pub use osc::*;
+
+mod midi;
+pub use midi::*;
+
+mod backend;
+pub use backend::*;
+
+#[cfg(test)]
+mod scratch;
+#[cfg(test)]
+pub use scratch::*;
+
+mod source;
+pub use source::*;
+
+mod recorder;
+pub use recorder::*;
+
+mod resample;
+pub use resample::*;
+
+mod duration;
+pub use duration::*;
+
+mod mix;
+pub use mix::*;
+
+mod bridge;
+pub use bridge::*;
+
+mod features;
+pub use features::*;
+
+mod segment;
+pub use segment::*;
+
+mod gate;
+pub use gate::*;
+
+mod corpus;
+pub use corpus::*;
+
+mod matcher;
+pub use matcher::*;
+
+mod selection;
+pub use selection::*;
+
+mod library;
+pub use library::*;
+
+mod target;
+pub use target::*;
+
 mod handlers;
 pub use handlers::*;
 
 fn main() {
-    run::<DictionaryHandlerEvent>();
+    let options = match Options::from_args::<(), _>(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}\n\n{}", e, USAGE);
+            return;
+        }
+    };
+
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    if let Err(e) = run::<DictionaryHandlerEvent>(options) {
+        println!("abort! {}", e);
+    }
 }
 
-fn run<T>() -> Result<(), Error<T>> {
+fn run<T>(options: Options) -> Result<(), Error<T>> {
+    let config = match options.config {
+        Some(ref path) => Config::from_path::<T>(path),
+        None => Config::load::<T>(),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            println!("could not read config, using defaults: {}", e);
+            Config::default()
+        }
+    };
+
+    let keymap = match Keymap::from_config::<T>(&config.keys) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            println!("could not read key bindings, using defaults: {}", e);
+            Keymap::default()
+        }
+    };
+
+    let sample_rate = options.sample_rate.unwrap_or(config.audio.sample_rate);
+
+    // Target files are found in the assets folder unless their paths say otherwise. The first
+    // target is split before anything starts, since there's nothing to play without it, and
+    // the rest in the background.
+    let assets = find_folder::Search::KidsThenParents(3, 5).for_folder("assets").unwrap();
+    let targets = if config.targets.is_empty() { vec![TargetConfig::new(PathBuf::from(DEFAULT_TARGET))] } else { config.targets.clone() };
+    let targets = targets.iter().map(|t| t.resolve(&assets)).collect();
+    let playlist = try!(Playlist::load::<T>(targets, sample_rate, &config.segmentation));
+
     crossbeam::scope(|scope| {
         let (audio_commands_producer, audio_commands_receiver) = bounded_spsc_queue::make::<AudioHandlerEvent>(256);
 
         let audio_playback_queue = Arc::new(SegQueue::<f64>::new());
         let (dict_prod, dict_cons) = mpsc::channel::<DictionaryHandlerEvent>();
         let (gui_prod, gui_recv) = mpsc::channel::<GuiHandlerEvent>();
+        let (status_prod, status_recv) = mpsc::channel::<StatusEvent>();
+        let (remote_prod, remote_recv) = mpsc::channel::<Remote>();
         let audio_dict_prod = dict_prod.clone();
+        let dict_gui_prod = gui_prod.clone();
+        let audio_status_prod = status_prod.clone();
         let apq1 = audio_playback_queue.clone();
         let apq2 = audio_playback_queue.clone();
-        scope.spawn(move || dictionary_handler(apq1, dict_cons));
-        scope.spawn(move || audio_handler::<DictionaryHandlerEvent>(apq2, audio_commands_receiver, audio_dict_prod, gui_prod));
-        match gui_handler::<DictionaryHandlerEvent>(audio_commands_producer, dict_prod, gui_recv) {
+        let context = DictionaryContext {
+            recording: config.recording.clone(),
+            segmentation: config.segmentation.clone(),
+            matching: config.matching.clone(),
+            session: options.session.clone(),
+            sample_rate: sample_rate,
+        };
+        let mut audio_config = config.audio.clone();
+        audio_config.sample_rate = sample_rate;
+        scope.spawn(move || dictionary_handler(apq1, dict_cons, dict_gui_prod, status_prod, playlist, context));
+        let backend_options = options.clone();
+        scope.spawn(move || {
+            let result = match backend_options.backend {
+                BackendKind::PortAudio => match PortAudioBackend::new(sample_rate) {
+                    Ok(mut backend) => {
+                        if let Err(e) = backend.set_output_buffer::<DictionaryHandlerEvent>(audio_config.output_frames_per_buffer, audio_config.output_latency) {
+                            println!("cannot use configured output buffer: {}", e);
+                        }
+                        audio_handler::<_, DictionaryHandlerEvent>(backend, &audio_config, apq2, audio_commands_receiver, audio_dict_prod, gui_prod, audio_status_prod)
+                    }
+                    Err(e) => Err(e),
+                },
+                BackendKind::File => {
+                    let backend = FileBackend::new(backend_options.input_file, backend_options.output_file, backend_options.speed, sample_rate);
+                    audio_handler::<_, DictionaryHandlerEvent>(backend, &audio_config, apq2, audio_commands_receiver, audio_dict_prod, gui_prod, audio_status_prod)
+                }
+            };
+            if let Err(e) = result {
+                println!("audio stopped: {}", e);
+            }
+        });
+
+        // The OSC server stops once both handlers have dropped their status senders
+        if let Some(osc_config) = config.osc.clone() {
+            let osc_remote_prod = remote_prod.clone();
+            scope.spawn(move || {
+                if let Err(e) = osc_handler::<DictionaryHandlerEvent>(osc_config, osc_remote_prod, status_recv) {
+                    println!("OSC server stopped: {}", e);
+                }
+            });
+        }
+
+        let mut controller = Controller::new(audio_commands_producer, dict_prod);
+        controller.recording = config.recording.always;
+
+        // MIDI input runs on midir's own thread for as long as the connection is held
+        let _midi_connection = match config.midi {
+            Some(ref midi_config) => {
+                let learned_path = options.config.clone().or(default_path()).map(|p| learned_midi_path(&p));
+                let midi_map = Arc::new(Mutex::new(MidiMap::new(midi_config, learned_path)));
+                match midi_handler::<DictionaryHandlerEvent>(midi_config, midi_map.clone(), remote_prod) {
+                    Ok(connection) => {
+                        controller = controller.with_midi(midi_map);
+                        Some(connection)
+                    }
+                    Err(e) => {
+                        println!("MIDI disabled: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let frontend = if options.headless {
+            headless_handler::<DictionaryHandlerEvent>(controller, gui_recv, remote_recv)
+        } else {
+            gui_handler::<DictionaryHandlerEvent>(controller, keymap, gui_recv, remote_recv)
+        };
+        match frontend {
             Err(e) => { 
                 println!("abort! {}", e);
             }
@@ -0,0 +1,364 @@
+use soundsym::*;
+
+use super::*;
+
+// Pitch is looked for between these, in Hz
+const MIN_PITCH: f64 = 50.;
+const MAX_PITCH: f64 = 1000.;
+// Samples from the middle of a segment the pitch is estimated over
+const PITCH_WINDOW: usize = 2048;
+// Normalised autocorrelation a peak needs to count as pitched
+const VOICING: f64 = 0.5;
+// Distance in semitones between a pitched segment and an unpitched one
+const UNPITCHED_DISTANCE: f64 = 12.;
+
+/// Which distance measure segments are matched by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatcherKind {
+    /// Euclidean distance between mean MFCCs
+    Euclidean,
+    /// Cosine distance between mean MFCCs, which ignores overall loudness
+    Cosine,
+    /// Dynamic time warping over the MFCC frames, which follows how a segment changes
+    Dtw,
+    /// Euclidean distance between mean MFCCs plus pitch and energy, see `WeightsConfig`
+    Weighted,
+}
+
+pub const MATCHERS: [MatcherKind; 4] = [MatcherKind::Euclidean, MatcherKind::Cosine, MatcherKind::Dtw, MatcherKind::Weighted];
+
+impl MatcherKind {
+    /// Name used in commands and the config file
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MatcherKind::Euclidean => "euclidean",
+            MatcherKind::Cosine => "cosine",
+            MatcherKind::Dtw => "dtw",
+            MatcherKind::Weighted => "weighted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MatcherKind> {
+        MATCHERS.iter().find(|k| k.name() == name).cloned()
+    }
+}
+
+/// `[matching]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchingConfig {
+    #[serde(default = "default_metric")]
+    pub metric: MatcherKind,
+    #[serde(default)]
+    pub weights: WeightsConfig,
+    #[serde(default)]
+    pub selection: SelectionConfig,
+}
+
+impl Default for MatchingConfig {
+    fn default() -> MatchingConfig {
+        MatchingConfig {
+            metric: default_metric(),
+            weights: WeightsConfig::default(),
+            selection: SelectionConfig::default(),
+        }
+    }
+}
+
+fn default_metric() -> MatcherKind {
+    MatcherKind::Euclidean
+}
+
+impl MatchingConfig {
+    pub fn matcher(&self) -> Box<Matcher> {
+        match self.metric {
+            MatcherKind::Euclidean => Box::new(EuclideanMatcher),
+            MatcherKind::Cosine => Box::new(CosineMatcher),
+            MatcherKind::Dtw => Box::new(DtwMatcher),
+            MatcherKind::Weighted => Box::new(WeightedMatcher::new(self.weights.clone())),
+        }
+    }
+}
+
+/// `[matching.weights]` section of the config file: how much each feature counts towards the
+/// weighted distance. Anything left out takes its default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WeightsConfig {
+    /// Per unit of Euclidean distance between mean MFCCs
+    pub mfcc: f64,
+    /// Per semitone of difference in pitch
+    pub pitch: f64,
+    /// Per dB of difference in level
+    pub energy: f64,
+}
+
+impl Default for WeightsConfig {
+    fn default() -> WeightsConfig {
+        WeightsConfig {
+            mfcc: 1.,
+            pitch: 0.5,
+            energy: 0.1,
+        }
+    }
+}
+
+/// Everything matchers compare segments by, worked out once per segment
+#[derive(Debug, Clone)]
+pub struct SegmentFeatures {
+    /// `NCOEFFS` per analysis frame, as `Sound::mfccs` gives them
+    pub mfccs: Vec<f64>,
+    pub mean: Vec<f64>,
+    /// RMS level in dBFS
+    pub energy: f64,
+    /// Fundamental in Hz, if the segment is pitched
+    pub pitch: Option<f64>,
+}
+
+impl SegmentFeatures {
+    pub fn new(samples: &[f64], mfccs: Vec<f64>, sample_rate: f64) -> SegmentFeatures {
+        SegmentFeatures {
+            mean: mean_frame(&mfccs[..]),
+            mfccs: mfccs,
+            energy: rms_level(samples),
+            pitch: estimate_pitch(samples, sample_rate),
+        }
+    }
+
+    /// The first MFCC frame, or the mean if there are no whole frames
+    pub fn first_frame(&self) -> &[f64] {
+        if self.mfccs.len() >= NCOEFFS { &self.mfccs[..NCOEFFS] } else { &self.mean[..] }
+    }
+
+    pub fn last_frame(&self) -> &[f64] {
+        let frames = self.mfccs.len() / NCOEFFS;
+        if frames > 0 { &self.mfccs[(frames - 1) * NCOEFFS..frames * NCOEFFS] } else { &self.mean[..] }
+    }
+}
+
+/// Measures how far a candidate segment is from the target segment it would stand in for.
+/// Smaller is closer.
+pub trait Matcher {
+    fn distance(&self, target: &SegmentFeatures, candidate: &SegmentFeatures) -> f64;
+}
+
+pub struct EuclideanMatcher;
+
+impl Matcher for EuclideanMatcher {
+    fn distance(&self, target: &SegmentFeatures, candidate: &SegmentFeatures) -> f64 {
+        euclidean(&target.mean[..], &candidate.mean[..])
+    }
+}
+
+pub struct CosineMatcher;
+
+impl Matcher for CosineMatcher {
+    fn distance(&self, target: &SegmentFeatures, candidate: &SegmentFeatures) -> f64 {
+        let dot: f64 = target.mean.iter().zip(candidate.mean.iter()).map(|(a, b)| a * b).sum();
+        let norms = norm(&target.mean[..]) * norm(&candidate.mean[..]);
+        if norms == 0. { 1. } else { 1. - dot / norms }
+    }
+}
+
+/// Compares whole sequences of MFCC frames, lining them up in time first. The cost is
+/// divided by the length of the two sequences together, so short segments aren't favoured.
+pub struct DtwMatcher;
+
+impl Matcher for DtwMatcher {
+    fn distance(&self, target: &SegmentFeatures, candidate: &SegmentFeatures) -> f64 {
+        let a: Vec<&[f64]> = target.mfccs.chunks(NCOEFFS).filter(|f| f.len() == NCOEFFS).collect();
+        let b: Vec<&[f64]> = candidate.mfccs.chunks(NCOEFFS).filter(|f| f.len() == NCOEFFS).collect();
+        if a.is_empty() || b.is_empty() {
+            return euclidean(&target.mean[..], &candidate.mean[..]);
+        }
+
+        // Only the previous row of the cost matrix is needed at any time
+        let inf = ::std::f64::INFINITY;
+        let mut previous = vec![inf; b.len() + 1];
+        let mut row = vec![inf; b.len() + 1];
+        previous[0] = 0.;
+        for x in a.iter() {
+            row[0] = inf;
+            for (j, y) in b.iter().enumerate() {
+                let best = previous[j].min(previous[j + 1]).min(row[j]);
+                row[j + 1] = euclidean(x, y) + best;
+            }
+            ::std::mem::swap(&mut previous, &mut row);
+        }
+        previous[b.len()] / (a.len() + b.len()) as f64
+    }
+}
+
+pub struct WeightedMatcher {
+    weights: WeightsConfig,
+}
+
+impl WeightedMatcher {
+    pub fn new(weights: WeightsConfig) -> WeightedMatcher {
+        WeightedMatcher { weights: weights }
+    }
+}
+
+impl Matcher for WeightedMatcher {
+    fn distance(&self, target: &SegmentFeatures, candidate: &SegmentFeatures) -> f64 {
+        let pitch = match (target.pitch, candidate.pitch) {
+            (Some(a), Some(b)) => (12. * (a / b).log2()).abs(),
+            (None, None) => 0.,
+            _ => UNPITCHED_DISTANCE,
+        };
+        self.weights.mfcc * euclidean(&target.mean[..], &candidate.mean[..])
+            + self.weights.pitch * pitch
+            + self.weights.energy * (target.energy - candidate.energy).abs()
+    }
+}
+
+/// Averages flat MFCC frames, `NCOEFFS` to a frame
+pub fn mean_frame(mfccs: &[f64]) -> Vec<f64> {
+    let frames = mfccs.len() / NCOEFFS;
+    let mut mean = vec![0.; NCOEFFS];
+    for frame in mfccs.chunks(NCOEFFS).take(frames) {
+        for (m, x) in mean.iter_mut().zip(frame.iter()) {
+            *m += *x / frames as f64;
+        }
+    }
+    mean
+}
+
+/// Picks the strongest peak in the normalised autocorrelation of the middle of the segment
+fn estimate_pitch(samples: &[f64], sample_rate: f64) -> Option<f64> {
+    let length = samples.len().min(PITCH_WINDOW);
+    let start = (samples.len() - length) / 2;
+    let window = &samples[start..start + length];
+    let min_lag = (sample_rate / MAX_PITCH) as usize;
+    let max_lag = ((sample_rate / MIN_PITCH) as usize).min(length / 2);
+    let power: f64 = window.iter().map(|s| s * s).sum();
+    if min_lag == 0 || min_lag >= max_lag || power == 0. {
+        return None;
+    }
+
+    let mut best: Option<(usize, f64)> = None;
+    for lag in min_lag..max_lag {
+        let correlation: f64 = window.iter().zip(window[lag..].iter()).map(|(a, b)| a * b).sum();
+        // Scaled for the shrinking overlap, so longer lags aren't penalised
+        let correlation = correlation / power * length as f64 / (length - lag) as f64;
+        if best.map(|(_, b)| correlation > b).unwrap_or(true) {
+            best = Some((lag, correlation));
+        }
+    }
+    match best {
+        Some((lag, correlation)) if correlation >= VOICING => Some(sample_rate / lag as f64),
+        _ => None,
+    }
+}
+
+pub fn euclidean(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt()
+}
+
+fn norm(a: &[f64]) -> f64 {
+    a.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    // A frame with `x` and `y` as its first two coefficients
+    fn frame(x: f64, y: f64) -> Vec<f64> {
+        let mut frame = vec![0.; NCOEFFS];
+        frame[0] = x;
+        frame[1] = y;
+        frame
+    }
+
+    fn features(frames: &[Vec<f64>]) -> SegmentFeatures {
+        let mfccs: Vec<f64> = frames.iter().flat_map(|f| f.iter().cloned()).collect();
+        SegmentFeatures { mean: mean_frame(&mfccs[..]), mfccs: mfccs, energy: -20., pitch: None }
+    }
+
+    #[test]
+    fn averages_whole_frames() {
+        let mut mfccs: Vec<f64> = frame(1., 2.).into_iter().chain(frame(3., 4.).into_iter()).collect();
+        mfccs.push(100.);
+        assert_eq!(mean_frame(&mfccs[..]), frame(2., 3.));
+        assert_eq!(mean_frame(&[]), frame(0., 0.));
+    }
+
+    #[test]
+    fn first_and_last_frames() {
+        let segment = features(&[frame(1., 0.), frame(2., 0.), frame(3., 0.)]);
+        assert_eq!(segment.first_frame(), &frame(1., 0.)[..]);
+        assert_eq!(segment.last_frame(), &frame(3., 0.)[..]);
+        let empty = SegmentFeatures { mfccs: Vec::new(), mean: frame(5., 5.), energy: 0., pitch: None };
+        assert_eq!(empty.first_frame(), &frame(5., 5.)[..]);
+        assert_eq!(empty.last_frame(), &frame(5., 5.)[..]);
+    }
+
+    #[test]
+    fn euclidean_compares_means() {
+        let a = features(&[frame(0., 0.)]);
+        let b = features(&[frame(3., 4.)]);
+        assert_eq!(EuclideanMatcher.distance(&a, &b), 5.);
+        assert_eq!(EuclideanMatcher.distance(&b, &b), 0.);
+    }
+
+    #[test]
+    fn cosine_ignores_loudness() {
+        let a = features(&[frame(1., 1.)]);
+        assert!(CosineMatcher.distance(&a, &features(&[frame(3., 3.)])).abs() < 1e-9);
+        assert!((CosineMatcher.distance(&a, &features(&[frame(1., -1.)])) - 1.).abs() < 1e-9);
+        assert_eq!(CosineMatcher.distance(&a, &features(&[frame(0., 0.)])), 1.);
+    }
+
+    #[test]
+    fn dtw_lines_up_stretched_sequences() {
+        let a = features(&[frame(0., 0.), frame(1., 0.), frame(2., 0.)]);
+        let stretched = features(&[frame(0., 0.), frame(0., 0.), frame(1., 0.), frame(1., 0.), frame(2., 0.)]);
+        let reversed = features(&[frame(2., 0.), frame(1., 0.), frame(0., 0.)]);
+        assert_eq!(DtwMatcher.distance(&a, &stretched), 0.);
+        assert!(DtwMatcher.distance(&a, &reversed) > 0.);
+        // Means are the same, so only the DTW tells them apart
+        assert_eq!(EuclideanMatcher.distance(&a, &reversed), 0.);
+    }
+
+    #[test]
+    fn dtw_falls_back_on_means_without_frames() {
+        let a = SegmentFeatures { mfccs: Vec::new(), mean: frame(0., 0.), energy: 0., pitch: None };
+        let b = features(&[frame(3., 4.)]);
+        assert_eq!(DtwMatcher.distance(&a, &b), 5.);
+    }
+
+    #[test]
+    fn weighted_adds_pitch_and_energy() {
+        let matcher = WeightedMatcher::new(WeightsConfig::default());
+        let mut a = features(&[frame(0., 0.)]);
+        let mut b = features(&[frame(3., 4.)]);
+        a.pitch = Some(220.);
+        b.pitch = Some(440.);
+        b.energy = a.energy - 10.;
+        // 5 for the MFCCs, an octave at 0.5 a semitone and 10dB at 0.1 a dB
+        assert!((matcher.distance(&a, &b) - 12.).abs() < 1e-9);
+        b.pitch = None;
+        assert!((matcher.distance(&a, &b) - (5. + 0.5 * UNPITCHED_DISTANCE + 1.)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn estimates_the_pitch_of_a_tone() {
+        // Low enough that no multiple of its period is in the range searched, which would make
+        // an octave below just as likely
+        let tone: Vec<f64> = (0..4096).map(|n| (2. * PI * 100. * n as f64 / 8000.).sin()).collect();
+        let pitch = estimate_pitch(&tone, 8000.).unwrap();
+        assert!((pitch - 100.).abs() < 2.);
+        assert_eq!(estimate_pitch(&vec![0.; 4096], 8000.), None);
+        assert_eq!(estimate_pitch(&[0.5; 10], 8000.), None);
+    }
+
+    #[test]
+    fn kinds_go_by_their_names() {
+        for kind in MATCHERS.iter() {
+            assert_eq!(MatcherKind::from_name(kind.name()), Some(*kind));
+        }
+        assert_eq!(MatcherKind::from_name("manhattan"), None);
+    }
+}
@@ -0,0 +1,412 @@
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use toml;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+
+use super::*;
+
+// Range a CC sweeps when a mapping doesn't give one
+const DEFAULT_THRESHOLD_RANGE: (usize, usize) = (1, 20);
+const DEFAULT_DEPTH_RANGE: (usize, usize) = (1, 10);
+
+/// File next to the config file that learned mappings are saved to
+pub const LEARNED_MIDI_FILE: &'static str = "midi-learned.toml";
+
+/// `[midi]` section of the config file. MIDI input only runs when the section is present.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MidiConfig {
+    /// Connect to the first input port whose name contains this, or the first port at all
+    pub port: Option<String>,
+    /// Create a virtual input port for other software to connect to instead (not on Windows)
+    #[serde(rename = "virtual", default)]
+    pub virtual_port: bool,
+    #[serde(default)]
+    pub mappings: Vec<MidiMapping>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlKind {
+    Note,
+    Cc,
+}
+
+/// Ties a note or controller to something to do. Notes and CCs can trigger any action (a CC
+/// fires when it crosses the halfway point, which suits footswitches); CCs can also sweep the
+/// threshold or depth between `min` and `max`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MidiMapping {
+    pub kind: ControlKind,
+    /// 0-15
+    pub channel: u8,
+    pub number: u8,
+    /// An action name as in `[keys]`, or "threshold" or "depth"
+    pub target: String,
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+/// Contents of the learned mappings file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct LearnedMappings {
+    #[serde(default)]
+    mappings: Vec<MidiMapping>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MidiTarget {
+    Trigger(Action),
+    Threshold(usize, usize),
+    Depth(usize, usize),
+}
+
+impl MidiMapping {
+    fn target(&self) -> Option<MidiTarget> {
+        match self.target.as_str() {
+            "threshold" => {
+                let (min, max) = DEFAULT_THRESHOLD_RANGE;
+                Some(MidiTarget::Threshold(self.min.unwrap_or(min), self.max.unwrap_or(max)))
+            }
+            "depth" => {
+                let (min, max) = DEFAULT_DEPTH_RANGE;
+                Some(MidiTarget::Depth(self.min.unwrap_or(min), self.max.unwrap_or(max)))
+            }
+            name => Action::from_name(name).map(MidiTarget::Trigger),
+        }
+    }
+}
+
+/// Everything that can be assigned by MIDI learn
+pub fn learnable_targets() -> Vec<&'static str> {
+    let mut targets = vec!["threshold", "depth"];
+    targets.extend(ACTIONS.iter().filter(|a| **a != Action::ToggleHelp).map(|a| a.name()));
+    targets
+}
+
+/// Where learned mappings are kept for the given config file
+pub fn learned_midi_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name(LEARNED_MIDI_FILE)
+}
+
+/// Turns raw MIDI messages into requests for the frontend, and learns new mappings. Learned
+/// mappings are saved straight away to a file of their own, so the config file is left as
+/// it was written.
+pub struct MidiMap {
+    mappings: Vec<MidiMapping>,
+    // The ones that were learned, here or in an earlier session; only these are saved
+    learned: Vec<MidiMapping>,
+    learning: Option<String>,
+    // Last value seen from each controller
+    values: HashMap<(u8, u8), u8>,
+    learned_path: Option<PathBuf>,
+}
+
+impl MidiMap {
+    /// Starts from the mappings in the config file, with any learned before at `learned_path`
+    /// taking over from them. Without a path nothing learned is saved.
+    pub fn new(config: &MidiConfig, learned_path: Option<PathBuf>) -> MidiMap {
+        let mut map = MidiMap {
+            mappings: config.mappings.clone(),
+            learned: Vec::new(),
+            learning: None,
+            values: HashMap::new(),
+            learned_path: None,
+        };
+        if let Some(ref path) = learned_path {
+            match load_learned::<()>(path) {
+                Ok(learned) => for mapping in learned.into_iter() {
+                    map.add(mapping);
+                },
+                Err(e) => println!("ignoring learned MIDI mappings: {}", e),
+            }
+        }
+        map.learned_path = learned_path;
+        map
+    }
+
+    /// The next note or controller to arrive will be mapped to `target`
+    pub fn learn(&mut self, target: &str) -> Result<(), String> {
+        if !learnable_targets().contains(&target) {
+            return Err(format!("can't map MIDI to {}", target));
+        }
+        println!("MIDI learn: move a control to map it to {}", target);
+        self.learning = Some(target.to_string());
+        Ok(())
+    }
+
+    pub fn learning(&self) -> Option<&str> {
+        self.learning.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn handle(&mut self, message: &[u8]) -> Option<Remote> {
+        if message.len() < 3 {
+            return None;
+        }
+
+        let channel = message[0] & 0x0f;
+        let (kind, number, value) = match message[0] & 0xf0 {
+            0x90 if message[2] > 0 => (ControlKind::Note, message[1], message[2]),
+            0xb0 => (ControlKind::Cc, message[1], message[2]),
+            // Note offs and everything else
+            _ => return None,
+        };
+
+        if let Some(target) = self.learning.take() {
+            self.assign(kind, channel, number, target);
+            return None;
+        }
+
+        let previous = if kind == ControlKind::Cc {
+            self.values.insert((channel, number), value)
+        } else {
+            None
+        };
+
+        let target = match self.mappings.iter()
+            .find(|m| m.kind == kind && m.channel == channel && m.number == number)
+            .and_then(|m| m.target())
+        {
+            Some(target) => target,
+            None => return None,
+        };
+
+        match (target, kind) {
+            (MidiTarget::Trigger(action), ControlKind::Note) => Some(Remote::Perform(action)),
+            (MidiTarget::Trigger(action), ControlKind::Cc) => {
+                if value >= 64 && previous.map(|p| p < 64).unwrap_or(true) {
+                    Some(Remote::Perform(action))
+                } else {
+                    None
+                }
+            }
+            // Only pass sweeps on when they land on a new value
+            (MidiTarget::Threshold(min, max), ControlKind::Cc) => {
+                let x = scale(value, min, max);
+                if previous.map(|p| scale(p, min, max)) != Some(x) { Some(Remote::Threshold(x)) } else { None }
+            }
+            (MidiTarget::Depth(min, max), ControlKind::Cc) => {
+                let x = scale(value, min, max);
+                if previous.map(|p| scale(p, min, max)) != Some(x) { Some(Remote::Depth(x)) } else { None }
+            }
+            // Notes can't sweep anything
+            _ => None,
+        }
+    }
+
+    fn assign(&mut self, kind: ControlKind, channel: u8, number: u8, target: String) {
+        println!("MIDI learn: {:?} {} on channel {} -> {}", kind, number, channel + 1, target);
+        self.add(MidiMapping {
+            kind: kind,
+            channel: channel,
+            number: number,
+            target: target,
+            min: None,
+            max: None,
+        });
+
+        if let Some(ref path) = self.learned_path {
+            if let Err(e) = save_learned::<()>(&self.learned, path) {
+                println!("could not save MIDI mappings: {}", e);
+            }
+        }
+    }
+
+    /// Adds a learned mapping in place of any other for the same control or target
+    fn add(&mut self, mapping: MidiMapping) {
+        {
+            let replaced = |m: &MidiMapping| {
+                (m.kind == mapping.kind && m.channel == mapping.channel && m.number == mapping.number)
+                    || m.target == mapping.target
+            };
+            self.mappings.retain(|m| !replaced(m));
+            self.learned.retain(|m| !replaced(m));
+        }
+        self.mappings.push(mapping.clone());
+        self.learned.push(mapping);
+    }
+}
+
+/// Reads learned mappings. There being no file yet just means nothing has been learned.
+fn load_learned<T>(path: &Path) -> Result<Vec<MidiMapping>, Error<T>> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => { try!(file.read_to_string(&mut contents)); }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::from(e)),
+    }
+    let learned: LearnedMappings = try!(toml::from_str(&contents)
+                                        .map_err(|e| Error::String(format!("cannot read {}: {}", path.display(), e))));
+    Ok(learned.mappings)
+}
+
+fn save_learned<T>(mappings: &[MidiMapping], path: &Path) -> Result<(), Error<T>> {
+    let learned = LearnedMappings { mappings: mappings.to_vec() };
+    let contents = try!(toml::to_string(&learned)
+                        .map_err(|e| Error::String(format!("cannot serialize MIDI mappings: {}", e))));
+    let mut file = try!(File::create(path));
+    try!(file.write_all(contents.as_bytes()));
+    Ok(())
+}
+
+/// Maps 0-127 onto min..max inclusive
+fn scale(value: u8, min: usize, max: usize) -> usize {
+    if max <= min {
+        return min;
+    }
+    min + ((value as f64 / 127.) * (max - min) as f64).round() as usize
+}
+
+/// Opens the configured MIDI input and starts passing requests to the frontend. Input stops
+/// when the returned connection is dropped.
+pub fn midi_handler<T>(config: &MidiConfig, map: Arc<Mutex<MidiMap>>, remote_prod: mpsc::Sender<Remote>) -> Result<MidiInputConnection<()>, Error<T>> {
+    let mut input = try!(MidiInput::new("reconstruction")
+                         .map_err(|e| Error::String(format!("cannot open MIDI input: {}", e))));
+    input.ignore(Ignore::All);
+
+    let callback = move |_: u64, message: &[u8], _: &mut ()| {
+        let remote = match map.lock() {
+            Ok(mut map) => map.handle(message),
+            Err(_) => None,
+        };
+        if let Some(remote) = remote {
+            if remote_prod.send(remote).is_err() {
+                println!("MIDI {:?} ignored: the frontend has stopped", remote);
+            }
+        }
+    };
+
+    if config.virtual_port {
+        println!("created virtual MIDI input \"reconstruction\"");
+        return connect_virtual(input, callback);
+    }
+
+    let port = try!((0..input.port_count()).find(|&i| {
+        match (input.port_name(i), config.port.as_ref()) {
+            (Ok(ref name), Some(wanted)) => name.contains(wanted.as_str()),
+            (Ok(_), None) => true,
+            (Err(_), _) => false,
+        }
+    }).ok_or(Error::String("no matching MIDI input port".to_string())));
+
+    println!("listening for MIDI on {}", input.port_name(port).unwrap_or("unknown port".to_string()));
+    input.connect(port, "reconstruction-in", callback, ())
+        .map_err(|e| Error::String(format!("cannot connect to MIDI input: {}", e)))
+}
+
+#[cfg(unix)]
+fn connect_virtual<T, F>(input: MidiInput, callback: F) -> Result<MidiInputConnection<()>, Error<T>>
+    where F: FnMut(u64, &[u8], &mut ()) + Send + 'static
+{
+    use midir::os::unix::VirtualInput;
+    input.create_virtual("reconstruction", callback, ())
+        .map_err(|e| Error::String(format!("cannot create virtual MIDI input: {}", e)))
+}
+
+#[cfg(not(unix))]
+fn connect_virtual<T, F>(_: MidiInput, _: F) -> Result<MidiInputConnection<()>, Error<T>>
+    where F: FnMut(u64, &[u8], &mut ()) + Send + 'static
+{
+    Err(Error::String("virtual MIDI ports aren't supported on this platform".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(kind: ControlKind, channel: u8, number: u8, target: &str) -> MidiMapping {
+        MidiMapping {
+            kind: kind,
+            channel: channel,
+            number: number,
+            target: target.to_string(),
+            min: None,
+            max: None,
+        }
+    }
+
+    fn config(mappings: Vec<MidiMapping>) -> MidiConfig {
+        MidiConfig { port: None, virtual_port: false, mappings: mappings }
+    }
+
+    fn map(mappings: Vec<MidiMapping>) -> MidiMap {
+        MidiMap::new(&config(mappings), None)
+    }
+
+    #[test]
+    fn cc_sweeps_threshold_across_its_range() {
+        let mut map = map(vec![mapping(ControlKind::Cc, 0, 7, "threshold")]);
+        assert_eq!(map.handle(&[0xb0, 7, 0]), Some(Remote::Threshold(1)));
+        assert_eq!(map.handle(&[0xb0, 7, 64]), Some(Remote::Threshold(11)));
+        assert_eq!(map.handle(&[0xb0, 7, 127]), Some(Remote::Threshold(20)));
+        // Nothing new to report
+        assert_eq!(map.handle(&[0xb0, 7, 127]), None);
+    }
+
+    #[test]
+    fn cc_range_and_channel_come_from_the_mapping() {
+        let mut depth = mapping(ControlKind::Cc, 1, 10, "depth");
+        depth.min = Some(2);
+        depth.max = Some(4);
+        let mut map = map(vec![depth]);
+        assert_eq!(map.handle(&[0xb1, 10, 127]), Some(Remote::Depth(4)));
+        assert_eq!(map.handle(&[0xb1, 10, 0]), Some(Remote::Depth(2)));
+        assert_eq!(map.handle(&[0xb0, 10, 127]), None);
+    }
+
+    #[test]
+    fn cc_triggers_when_it_crosses_halfway() {
+        let mut map = map(vec![mapping(ControlKind::Cc, 0, 64, "play")]);
+        assert_eq!(map.handle(&[0xb0, 64, 127]), Some(Remote::Perform(Action::Play)));
+        assert_eq!(map.handle(&[0xb0, 64, 100]), None);
+        assert_eq!(map.handle(&[0xb0, 64, 0]), None);
+        assert_eq!(map.handle(&[0xb0, 64, 64]), Some(Remote::Perform(Action::Play)));
+    }
+
+    #[test]
+    fn notes_trigger_on_note_on_only() {
+        let mut map = map(vec![mapping(ControlKind::Note, 0, 60, "reconstruct")]);
+        assert_eq!(map.handle(&[0x90, 60, 100]), Some(Remote::Perform(Action::Reconstruct)));
+        assert_eq!(map.handle(&[0x90, 60, 0]), None);
+        assert_eq!(map.handle(&[0x80, 60, 64]), None);
+        assert_eq!(map.handle(&[0x90, 60]), None);
+    }
+
+    #[test]
+    fn learns_the_next_control() {
+        let mut map = map(Vec::new());
+        assert!(map.learn("nothing").is_err());
+        map.learn("depth").unwrap();
+        assert_eq!(map.handle(&[0xb2, 20, 5]), None);
+        assert_eq!(map.learning(), None);
+        assert_eq!(map.handle(&[0xb2, 20, 127]), Some(Remote::Depth(10)));
+    }
+
+    #[test]
+    fn learned_mappings_outlive_the_session() {
+        let dir = ScratchDir::new("midi-learned");
+        let path = dir.join(LEARNED_MIDI_FILE);
+        let config = config(vec![mapping(ControlKind::Cc, 0, 7, "threshold"), mapping(ControlKind::Note, 0, 60, "play")]);
+
+        let mut map = MidiMap::new(&config, Some(path.clone()));
+        map.learn("threshold").unwrap();
+        map.handle(&[0xb0, 8, 0]);
+        assert!(path.exists());
+
+        // The learned mapping takes over the target from the one in the config file, and the
+        // rest of the config is kept
+        let mut map = MidiMap::new(&config, Some(path.clone()));
+        assert_eq!(map.handle(&[0xb0, 7, 127]), None);
+        assert_eq!(map.handle(&[0xb0, 8, 127]), Some(Remote::Threshold(20)));
+        assert_eq!(map.handle(&[0x90, 60, 100]), Some(Remote::Perform(Action::Play)));
+    }
+
+    #[test]
+    fn nothing_learned_yet() {
+        let dir = ScratchDir::new("midi-unlearned");
+        assert!(load_learned::<()>(&dir.join(LEARNED_MIDI_FILE)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use std::f32::consts::PI;
+
+use super::*;
+
+/// How the audio callback maps device channels to and from the mono signal the rest of the
+/// app works with, and how loud each part is. It's `Copy` so it can be handed to the callback
+/// over a queue without allocating on the audio thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelMix {
+    /// Bit `n` set captures input channel `n`. Selected channels are averaged.
+    pub inputs: u32,
+    /// -1 is hard left, 1 hard right
+    pub pan: f32,
+    /// Applied to the input before it's captured or monitored
+    pub input_gain: f32,
+    /// Level of the input passed straight through to the output
+    pub monitor: f32,
+    /// Level of the reconstruction in the output
+    pub reconstruction: f32,
+}
+
+impl Default for ChannelMix {
+    fn default() -> ChannelMix {
+        ChannelMix {
+            inputs: 1,
+            pan: 0.,
+            input_gain: 1.,
+            // Off to begin with, so there's no feedback with speakers and an open mic
+            monitor: 0.,
+            reconstruction: 1.,
+        }
+    }
+}
+
+impl ChannelMix {
+    /// Selects the given channels, counting from 0. Channels past `MAX_INPUT_CHANNELS` are
+    /// ignored, and selecting nothing falls back on the first channel.
+    pub fn set_inputs(&mut self, channels: &[usize]) {
+        self.inputs = channels.iter()
+            .filter(|c| **c < MAX_INPUT_CHANNELS)
+            .fold(0, |mask, c| mask | 1 << c);
+        if self.inputs == 0 {
+            self.inputs = 1;
+        }
+    }
+
+    /// Selected channels, counting from 0
+    pub fn input_channels(&self) -> Vec<usize> {
+        (0..MAX_INPUT_CHANNELS).filter(|c| self.inputs & 1 << c != 0).collect()
+    }
+
+    /// Mixes a block of interleaved input down to mono, at the input gain. Selected channels
+    /// the device doesn't have are skipped.
+    pub fn mix_input(&self, in_buffer: &[f32], channels: usize, block: &mut [f32]) {
+        let selected = (0..channels).filter(|c| self.inputs & 1 << c != 0).count();
+        for (frame, s) in in_buffer.chunks(channels).zip(block.iter_mut()) {
+            *s = if selected == 0 {
+                0.
+            } else {
+                frame.iter().enumerate()
+                    .filter(|&(c, _)| self.inputs & 1 << c != 0)
+                    .fold(0., |acc, (_, x)| acc + x) * self.input_gain / selected as f32
+            };
+        }
+    }
+
+    pub fn set_level(&mut self, level: Level, value: f32) {
+        let value = value.max(0.);
+        match level {
+            Level::InputGain => self.input_gain = value,
+            Level::Monitor => self.monitor = value,
+            Level::Reconstruction => self.reconstruction = value,
+        }
+    }
+
+    pub fn level(&self, level: Level) -> f32 {
+        match level {
+            Level::InputGain => self.input_gain,
+            Level::Monitor => self.monitor,
+            Level::Reconstruction => self.reconstruction,
+        }
+    }
+
+    /// Mono output: the reconstruction with the monitored input on top
+    pub fn output_sample(&self, reconstruction: f32, input: f32) -> f32 {
+        reconstruction * self.reconstruction + input * self.monitor
+    }
+
+    /// Equal-power gains for the left and right outputs
+    pub fn pan_gains(&self) -> (f32, f32) {
+        let angle = (self.pan.max(-1.).min(1.) + 1.) * PI / 4.;
+        (angle.cos(), angle.sin())
+    }
+
+    /// Writes one mono sample to an interleaved output frame. Mono devices get it unpanned;
+    /// channels beyond the first two are silent.
+    pub fn spread_output(&self, sample: f32, frame: &mut [f32]) {
+        if frame.len() == 1 {
+            frame[0] = sample;
+            return;
+        }
+        let (left, right) = self.pan_gains();
+        for (c, s) in frame.iter_mut().enumerate() {
+            *s = match c {
+                0 => sample * left,
+                1 => sample * right,
+                _ => 0.,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixes_selected_channels_to_mono() {
+        let mut mix = ChannelMix::default();
+        mix.set_inputs(&[0, 2]);
+        mix.input_gain = 2.;
+        let input = [1., 5., 3., 0.5, 5., 0.5];
+        let mut block = [0.; 2];
+        mix.mix_input(&input, 3, &mut block);
+        assert_eq!(block, [4., 1.]);
+    }
+
+    #[test]
+    fn skips_channels_the_device_lacks() {
+        let mut mix = ChannelMix::default();
+        mix.set_inputs(&[1, 3]);
+        let mut block = [0.; 2];
+        mix.mix_input(&[0.25, 0.5, 0.75, 1.], 2, &mut block);
+        assert_eq!(block, [0.5, 1.]);
+
+        mix.set_inputs(&[5]);
+        mix.mix_input(&[0.25, 0.5, 0.75, 1.], 2, &mut block);
+        assert_eq!(block, [0., 0.]);
+    }
+
+    #[test]
+    fn selecting_nothing_captures_the_first_channel() {
+        let mut mix = ChannelMix::default();
+        mix.set_inputs(&[]);
+        assert_eq!(mix.input_channels(), vec![0]);
+        mix.set_inputs(&[MAX_INPUT_CHANNELS]);
+        assert_eq!(mix.input_channels(), vec![0]);
+    }
+
+    #[test]
+    fn pans_with_equal_power() {
+        let mut mix = ChannelMix::default();
+        for &pan in [-1., -0.3, 0., 0.7, 1.].iter() {
+            mix.pan = pan;
+            let (left, right) = mix.pan_gains();
+            assert!((left * left + right * right - 1.).abs() < 1e-6);
+        }
+
+        mix.pan = -1.;
+        let (left, right) = mix.pan_gains();
+        assert!((left - 1.).abs() < 1e-6 && right.abs() < 1e-6);
+        mix.pan = 0.;
+        let (left, right) = mix.pan_gains();
+        assert!((left - right).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spreads_output_over_the_first_two_channels() {
+        let mut mix = ChannelMix::default();
+        mix.pan = 1.;
+        let mut frame = [1.; 3];
+        mix.spread_output(0.5, &mut frame);
+        assert!(frame[0].abs() < 1e-6 && (frame[1] - 0.5).abs() < 1e-6);
+        assert_eq!(frame[2], 0.);
+
+        let mut mono = [0.];
+        mix.spread_output(0.5, &mut mono);
+        assert_eq!(mono, [0.5]);
+    }
+}
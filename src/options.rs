@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use super::*;
+
+pub const USAGE: &'static str = "\
+Usage: reconstruction [options]
+
+Options:
+    --headless        Run without a window, reading commands from stdin
+    --config PATH     Read settings from PATH instead of assets/config.toml
+    --session NAME    Name recordings of the input after NAME (default \"session\")
+    --backend NAME    Audio backend: portaudio (the default) or file
+    --sample-rate HZ  Run devices at HZ instead of the rate in the config file
+    --input-file PATH With the file backend, read input from this WAV instead of
+                      using silence
+    --output-file PATH
+                      With the file backend, write output to this WAV
+    --speed X         With the file backend, run at X times realtime, or as fast
+                      as possible if X is 0 (default 1)
+    -h, --help        Print this message";
+
+/// Command line options
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub headless: bool,
+    pub config: Option<PathBuf>,
+    pub session: String,
+    pub backend: BackendKind,
+    pub sample_rate: Option<f64>,
+    pub input_file: Option<PathBuf>,
+    pub output_file: Option<PathBuf>,
+    pub speed: f64,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            headless: false,
+            config: None,
+            session: "session".to_string(),
+            backend: BackendKind::PortAudio,
+            sample_rate: None,
+            input_file: None,
+            output_file: None,
+            speed: 1.,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args<T, I: Iterator<Item=String>>(mut args: I) -> Result<Options, Error<T>> {
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--config" => options.config = Some(PathBuf::from(try!(value(&arg, &mut args)))),
+                "--session" => options.session = try!(value(&arg, &mut args)),
+                "--backend" => {
+                    options.backend = match try!(value(&arg, &mut args)).as_str() {
+                        "portaudio" => BackendKind::PortAudio,
+                        "file" => BackendKind::File,
+                        other => return Err(Error::String(format!("unknown backend {}", other))),
+                    }
+                }
+                "--sample-rate" => {
+                    let rate = try!(value(&arg, &mut args));
+                    // Rates end up dividing sample counts, so only finite ones above 0 make sense
+                    options.sample_rate = match rate.parse::<f64>() {
+                        Ok(x) if x.is_finite() && x > 0. => Some(x),
+                        _ => return Err(Error::String(format!("--sample-rate {} is not a rate above 0", rate))),
+                    };
+                }
+                "--input-file" => options.input_file = Some(PathBuf::from(try!(value(&arg, &mut args)))),
+                "--output-file" => options.output_file = Some(PathBuf::from(try!(value(&arg, &mut args)))),
+                "--speed" => {
+                    let speed = try!(value(&arg, &mut args));
+                    options.speed = try!(speed.parse::<f64>()
+                                         .map_err(|_| Error::String(format!("--speed {} is not a number", speed))));
+                }
+                "-h" | "--help" => options.help = true,
+                _ => return Err(Error::String(format!("unknown option {}", arg))),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn value<T, I: Iterator<Item=String>>(option: &str, args: &mut I) -> Result<String, Error<T>> {
+    args.next().ok_or(Error::String(format!("{} needs a value", option)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, Error<()>> {
+        Options::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn takes_a_sample_rate() {
+        assert_eq!(parse(&["--sample-rate", "48000"]).unwrap().sample_rate, Some(48000.));
+        assert_eq!(parse(&[]).unwrap().sample_rate, None);
+    }
+
+    #[test]
+    fn rejects_rates_that_are_not_above_0() {
+        for rate in ["0", "-44100", "NaN", "inf", "fast"].iter() {
+            assert!(parse(&["--sample-rate", rate]).is_err(), "accepted {}", rate);
+        }
+        assert!(parse(&["--sample-rate"]).is_err());
+    }
+}
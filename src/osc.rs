@@ -0,0 +1,188 @@
+use rosc::{self, OscMessage, OscPacket, OscType};
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use super::*;
+
+/// `[osc]` section of the config file. The server only runs when the section is present.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OscConfig {
+    /// Address to listen for control messages on, e.g. "0.0.0.0:9000"
+    pub listen: String,
+    /// Where status messages go, if anywhere
+    pub send: Option<String>,
+}
+
+/// Translates an incoming message into a request for the frontend. Numeric arguments can be
+/// ints or floats, since Max in particular sends whichever it feels like.
+///
+/// * `/dsp/start`, `/dsp/stop`
+/// * `/reconstruct`, `/play`, `/stop`, `/loop`, `/record`, `/export`, `/clear`
+/// * `/threshold i`, `/depth i`
+/// * `/target i`, counting from 0 in the playlist
+pub fn remote_from_message(message: &OscMessage) -> Option<Remote> {
+    let arg = message.args.as_ref().and_then(|args| args.first()).and_then(|arg| {
+        match *arg {
+            OscType::Int(x) if x >= 0 => Some(x as usize),
+            OscType::Float(x) if x >= 0. => Some(x.round() as usize),
+            _ => None,
+        }
+    });
+
+    match (message.addr.as_str(), arg) {
+        ("/dsp/start", _) => Some(Remote::Perform(Action::StartDsp)),
+        ("/dsp/stop", _) => Some(Remote::Perform(Action::StopDsp)),
+        ("/reconstruct", _) => Some(Remote::Perform(Action::Reconstruct)),
+        ("/play", _) => Some(Remote::Perform(Action::Play)),
+        ("/stop", _) => Some(Remote::Perform(Action::StopPlayback)),
+        ("/loop", _) => Some(Remote::Perform(Action::ToggleLoop)),
+        ("/record", _) => Some(Remote::Perform(Action::ToggleRecord)),
+        ("/export", _) => Some(Remote::Perform(Action::Export)),
+        ("/clear", _) => Some(Remote::Perform(Action::Clear)),
+        ("/threshold", Some(x)) => Some(Remote::Threshold(x)),
+        ("/depth", Some(x)) => Some(Remote::Depth(x)),
+        ("/target", Some(x)) => Some(Remote::Target(x)),
+        _ => None,
+    }
+}
+
+/// Status messages sent back to the configured destination:
+///
+/// * `/status/segments ii` target and capture segment counts
+/// * `/status/playing i`
+/// * `/status/dsp i`
+/// * `/status/gate ii` segments kept and dropped by the gate
+/// * `/status/target i` the target being reconstructed
+pub fn message_from_status(status: &StatusEvent) -> OscMessage {
+    let (addr, args) = match *status {
+        StatusEvent::Segments { target, capture } =>
+            ("/status/segments", vec![OscType::Int(target as i32), OscType::Int(capture as i32)]),
+        StatusEvent::Playing(playing) => ("/status/playing", vec![OscType::Int(playing as i32)]),
+        StatusEvent::Dsp(running) => ("/status/dsp", vec![OscType::Int(running as i32)]),
+        StatusEvent::Gated { kept, dropped } =>
+            ("/status/gate", vec![OscType::Int(kept as i32), OscType::Int(dropped as i32)]),
+        StatusEvent::Target(idx) => ("/status/target", vec![OscType::Int(idx as i32)]),
+    };
+    OscMessage { addr: addr.to_string(), args: Some(args) }
+}
+
+fn messages(packet: OscPacket, into: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(message) => into.push(message),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                messages(packet, into);
+            }
+        }
+    }
+}
+
+/// Listens for OSC control messages and passes them on to the frontend, and forwards status
+/// from the handlers. Runs until the handlers have quit; socket errors along the way are
+/// reported and otherwise ignored.
+pub fn osc_handler<T>(config: OscConfig, remote_prod: mpsc::Sender<Remote>, status_recv: mpsc::Receiver<StatusEvent>) -> Result<(), Error<T>> {
+    let socket = try!(UdpSocket::bind(config.listen.as_str()));
+    // Wake up regularly to pass on status
+    try!(socket.set_read_timeout(Some(Duration::from_millis(10))));
+    println!("listening for OSC on {}", config.listen);
+
+    let destination: Option<SocketAddr> = match config.send {
+        Some(ref addr) => Some(try!(try!(addr.to_socket_addrs()).next()
+                                    .ok_or(Error::String(format!("cannot resolve {}", addr))))),
+        None => None,
+    };
+
+    let mut buf = [0u8; rosc::decoder::MTU];
+    let mut incoming = Vec::new();
+
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((size, _)) => {
+                match rosc::decoder::decode(&buf[..size]) {
+                    Ok(packet) => messages(packet, &mut incoming),
+                    Err(e) => println!("could not decode OSC packet: {:?}", e),
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => { }
+            Err(e) => {
+                println!("OSC receive failed: {}", e);
+                // Don't spin if the error is one that keeps coming back
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        for message in incoming.drain(..) {
+            match remote_from_message(&message) {
+                Some(remote) => if remote_prod.send(remote).is_err() { return Ok(()); },
+                None => println!("unhandled OSC message {}", message.addr),
+            }
+        }
+
+        loop {
+            match status_recv.try_recv() {
+                Ok(status) => {
+                    if let Some(ref destination) = destination {
+                        let packet = OscPacket::Message(message_from_status(&status));
+                        match rosc::encoder::encode(&packet) {
+                            Ok(bytes) => if let Err(e) = socket.send_to(&bytes[..], destination) {
+                                println!("could not send OSC status to {}: {}", destination, e);
+                            },
+                            Err(e) => println!("could not encode OSC packet: {:?}", e),
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage { addr: addr.to_string(), args: Some(args) }
+    }
+
+    #[test]
+    fn arguments_can_be_ints_or_floats() {
+        assert_eq!(remote_from_message(&message("/depth", vec![OscType::Int(3)])), Some(Remote::Depth(3)));
+        assert_eq!(remote_from_message(&message("/target", vec![OscType::Float(1.6)])), Some(Remote::Target(2)));
+        assert_eq!(remote_from_message(&message("/threshold", vec![OscType::Int(-1)])), None);
+        assert_eq!(remote_from_message(&message("/threshold", vec![])), None);
+    }
+
+    #[test]
+    fn threshold_message_reaches_the_frontend() {
+        // A port nobody is using, handed on to the server
+        let listen = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (remote_prod, remote_recv) = mpsc::channel();
+        let (status_prod, status_recv) = mpsc::channel();
+        let config = OscConfig { listen: listen.to_string(), send: None };
+        let server = thread::spawn(move || osc_handler::<()>(config, remote_prod, status_recv));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let packet = OscPacket::Message(message("/threshold", vec![OscType::Int(6)]));
+        let bytes = rosc::encoder::encode(&packet).unwrap();
+        // The server may not be listening yet, so keep sending until something comes through
+        let mut received = None;
+        for _ in 0..100 {
+            client.send_to(&bytes[..], listen).ok();
+            if let Ok(remote) = remote_recv.recv_timeout(Duration::from_millis(20)) {
+                received = Some(remote);
+                break;
+            }
+        }
+        assert_eq!(received, Some(Remote::Threshold(6)));
+
+        // Hanging up the status channel is what stops the server
+        drop(status_prod);
+        assert!(server.join().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,135 @@
+use chrono::Local;
+use hound;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use super::*;
+
+/// `[recording]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingConfig {
+    /// Record whenever there's input, without having to turn it on
+    #[serde(default)]
+    pub always: bool,
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> RecordingConfig {
+        RecordingConfig {
+            always: false,
+            directory: default_directory(),
+        }
+    }
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from("recordings")
+}
+
+/// Writes input blocks to a WAV file on a thread of its own, so the dictionary never waits on
+/// the disk. The file is finished when the recorder is dropped.
+pub struct Recorder {
+    path: PathBuf,
+    sender: Option<mpsc::Sender<[f32; BLOCK_SIZE]>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Starts a new file in `directory`, named `<session>-<date>-<time>.wav` with the time to
+    /// the millisecond. An existing file is never replaced.
+    pub fn start<T>(directory: &Path, session: &str, sample_rate: u32) -> Result<Recorder, Error<T>> {
+        try!(fs::create_dir_all(directory));
+        let name = format!("{}-{}", session, Local::now().format("%Y%m%d-%H%M%S%.3f"));
+        let (path, file) = try!(create_new(directory, &name));
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = try!(hound::WavWriter::new(BufWriter::new(file), spec)
+                              .map_err(|e| Error::String(format!("cannot create {}: {}", path.display(), e))));
+
+        let (sender, receiver) = mpsc::channel::<[f32; BLOCK_SIZE]>();
+        let thread_path = path.clone();
+        let thread = thread::spawn(move || {
+            for block in receiver.iter() {
+                for s in block.iter() {
+                    if let Err(e) = writer.write_sample(*s) {
+                        println!("recording to {} failed: {}", thread_path.display(), e);
+                        return;
+                    }
+                }
+            }
+            if let Err(e) = writer.finalize() {
+                println!("could not finish {}: {}", thread_path.display(), e);
+            }
+        });
+
+        println!("recording input to {}", path.display());
+        Ok(Recorder {
+            path: path,
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn push(&self, block: &[f32; BLOCK_SIZE]) {
+        if let Some(ref sender) = self.sender {
+            sender.send(*block).ok();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Hanging up lets the thread write out what's left and finalize the file
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        println!("finished recording {}", self.path.display());
+    }
+}
+
+/// Creates `<name>.wav` in `directory`, or `<name>-2.wav` and so on if that's taken
+fn create_new(directory: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    let mut n = 1;
+    loop {
+        let path = if n == 1 {
+            directory.join(format!("{}.wav", name))
+        } else {
+            directory.join(format!("{}-{}.wav", name, n))
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_replaces_a_file() {
+        let directory = ScratchDir::new("recorder-create-new");
+        let (first, _) = create_new(directory.path(), "take").unwrap();
+        let (second, _) = create_new(directory.path(), "take").unwrap();
+        assert_eq!(first, directory.join("take.wav"));
+        assert_eq!(second, directory.join("take-2.wav"));
+    }
+}
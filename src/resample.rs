@@ -0,0 +1,82 @@
+use std::f64::consts::PI;
+
+// Taps either side of each output sample
+const HALF_TAPS: isize = 16;
+
+/// Band-limited resampling with a Hann-windowed sinc. When going down in rate the cutoff drops
+/// with it, so nothing above the new Nyquist folds back in.
+pub fn resample(samples: &[f64], from: f64, to: f64) -> Vec<f64> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let ratio = to / from;
+    let cutoff = ratio.min(1.);
+    let out_len = (samples.len() as f64 * ratio).round() as usize;
+
+    (0..out_len).map(|n| {
+        // Position of this output sample in the input
+        let t = n as f64 / ratio;
+        let center = t.floor() as isize;
+        let mut acc = 0.;
+        for k in (center - HALF_TAPS + 1)..(center + HALF_TAPS + 1) {
+            if k < 0 || k as usize >= samples.len() {
+                continue;
+            }
+            let x = t - k as f64;
+            let window = 0.5 * (1. + (PI * x / HALF_TAPS as f64).cos());
+            acc += samples[k as usize] * cutoff * sinc(cutoff * x) * window;
+        }
+        acc
+    }).collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_rate_is_untouched() {
+        let samples = vec![0.1, -0.2, 0.3];
+        assert_eq!(resample(&samples, 44100., 44100.), samples);
+        assert!(resample(&[], 44100., 48000.).is_empty());
+    }
+
+    #[test]
+    fn length_follows_the_ratio() {
+        let samples = vec![0.; 441];
+        assert_eq!(resample(&samples, 44100., 48000.).len(), 480);
+        assert_eq!(resample(&samples, 44100., 22050.).len(), 221);
+    }
+
+    #[test]
+    fn doubling_keeps_the_original_samples() {
+        let samples: Vec<f64> = (0..64).map(|n| (n as f64 * 0.3).sin()).collect();
+        let doubled = resample(&samples, 22050., 44100.);
+        for (n, s) in samples.iter().enumerate() {
+            assert!((doubled[2 * n] - s).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn keeps_low_frequencies_and_drops_ones_above_the_new_nyquist() {
+        let tone = |hz: f64| -> Vec<f64> { (0..4410).map(|n| (2. * PI * hz * n as f64 / 44100.).sin()).collect() };
+        // Away from the ends, where the filter runs out of input
+        let rms = |s: &[f64]| -> f64 {
+            let middle = &s[s.len() / 4..3 * s.len() / 4];
+            (middle.iter().map(|x| x * x).sum::<f64>() / middle.len() as f64).sqrt()
+        };
+        let low = resample(&tone(1000.), 44100., 22050.);
+        assert!((rms(&low) - 0.5f64.sqrt()).abs() < 0.05);
+        let high = resample(&tone(15000.), 44100., 22050.);
+        assert!(rms(&high) < 0.05);
+    }
+}
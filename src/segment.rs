@@ -0,0 +1,455 @@
+use rustfft::FFTplanner;
+use rustfft::num_complex::Complex;
+use rustfft::num_traits::Zero;
+use soundsym::*;
+use rusty_machine::prelude::*;
+
+use std::borrow::Cow;
+use std::f64::consts::PI;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::*;
+
+/// Start and end of a segment, measured from the start of the sound, as
+/// `SoundDictionary::from_segments` takes them
+pub type Segment = (Duration, Duration);
+
+/// Splits a sound into the segments that make up a dictionary
+pub trait Segmenter {
+    fn segment(&self, sound: &Sound) -> Result<Vec<Segment>, String>;
+}
+
+/// Which segmenter to use on a source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmenterKind {
+    /// The partitioner trained on the target
+    Model,
+    /// Onsets in the spectral flux, see `OnsetSegmenter`
+    Onset,
+    /// Fixed-length grains, see `GrainSegmenter`
+    Grain,
+}
+
+pub const SEGMENTERS: [SegmenterKind; 3] = [SegmenterKind::Model, SegmenterKind::Onset, SegmenterKind::Grain];
+
+impl SegmenterKind {
+    /// Name used in commands and the config file
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SegmenterKind::Model => "model",
+            SegmenterKind::Onset => "onset",
+            SegmenterKind::Grain => "grain",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SegmenterKind> {
+        SEGMENTERS.iter().find(|k| k.name() == name).cloned()
+    }
+}
+
+/// `[segmentation]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentationConfig {
+    #[serde(default = "default_target_segmenter")]
+    pub target: SegmenterKind,
+    #[serde(default = "default_capture_segmenter")]
+    pub capture: SegmenterKind,
+    #[serde(default)]
+    pub onset: OnsetConfig,
+    #[serde(default)]
+    pub grain: GrainConfig,
+    #[serde(default)]
+    pub gate: GateConfig,
+}
+
+impl Default for SegmentationConfig {
+    fn default() -> SegmentationConfig {
+        SegmentationConfig {
+            target: default_target_segmenter(),
+            capture: default_capture_segmenter(),
+            onset: OnsetConfig::default(),
+            grain: GrainConfig::default(),
+            gate: GateConfig::default(),
+        }
+    }
+}
+
+fn default_target_segmenter() -> SegmenterKind {
+    SegmenterKind::Model
+}
+
+// The model often finds nothing to split in live input, so it isn't the default there
+fn default_capture_segmenter() -> SegmenterKind {
+    SegmenterKind::Onset
+}
+
+/// `[segmentation.onset]` section of the config file. Anything left out takes its default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OnsetConfig {
+    /// Analysis window in samples
+    pub window: usize,
+    /// Samples between analysis frames
+    pub hop: usize,
+    /// How many times its local median the spectral flux has to reach to count as an onset
+    pub sensitivity: f64,
+    /// Level in dBFS the input has to rise above to open the gate
+    pub gate_open: f64,
+    /// Level in dBFS it has to fall below to close it again
+    pub gate_close: f64,
+    /// Shortest segment in seconds. Onsets closer together are ignored, and shorter bursts
+    /// through the gate are dropped.
+    pub min_length: f64,
+    /// Longest segment in seconds. Anything longer is cut into equal pieces.
+    pub max_length: f64,
+}
+
+impl Default for OnsetConfig {
+    fn default() -> OnsetConfig {
+        OnsetConfig {
+            window: 1024,
+            hop: 256,
+            sensitivity: 1.5,
+            gate_open: -45.,
+            gate_close: -55.,
+            min_length: 0.05,
+            max_length: 1.,
+        }
+    }
+}
+
+/// `[segmentation.grain]` section of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrainConfig {
+    /// Grain length in seconds
+    pub length: f64,
+    /// Fraction of each grain shared with the next, from 0 for none up to 0.95
+    pub overlap: f64,
+}
+
+impl Default for GrainConfig {
+    fn default() -> GrainConfig {
+        GrainConfig {
+            length: 0.1,
+            overlap: 0.,
+        }
+    }
+}
+
+/// Segments with the `Partitioner`, which has to be trained on a sound first
+pub struct ModelSegmenter<'a> {
+    // Only missing while a parameter is being changed, since the builder methods take it by value
+    partitioner: Option<Partitioner<'a>>,
+}
+
+impl<'a> ModelSegmenter<'a> {
+    /// Trains the partitioner on `sound`
+    pub fn train(sound: Cow<'a, Sound>, threshold: usize, depth: usize) -> ModelSegmenter<'a> {
+        let mut partitioner = Partitioner::new(sound).threshold(threshold).depth(depth);
+        partitioner.train();
+        ModelSegmenter { partitioner: Some(partitioner) }
+    }
+
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.partitioner = self.partitioner.take().map(|p| p.threshold(threshold));
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.partitioner = self.partitioner.take().map(|p| p.depth(depth));
+    }
+}
+
+impl<'a> Segmenter for ModelSegmenter<'a> {
+    fn segment(&self, sound: &Sound) -> Result<Vec<Segment>, String> {
+        let partitioner = try!(self.partitioner.as_ref().ok_or("partitioner is missing".to_string()));
+        let rows = sound.mfccs().len() / NCOEFFS;
+        let data = Matrix::new(rows, NCOEFFS, sound.mfccs().clone());
+        let predictions = try!(partitioner.predict(&data).map_err(|e| format!("{:?}", e)));
+        partitioner.partition(predictions).map_err(|e| format!("{:?}", e))
+    }
+}
+
+/// Trains a `ModelSegmenter` on a thread of its own, since training takes long enough to hold
+/// up everything else. Dropping it throws the result away.
+pub struct ModelTrainer {
+    result: mpsc::Receiver<ModelSegmenter<'static>>,
+}
+
+impl ModelTrainer {
+    pub fn spawn(sound: Sound, threshold: usize, depth: usize) -> ModelTrainer {
+        let (prod, result) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = prod.send(ModelSegmenter::train(Cow::Owned(sound), threshold, depth));
+        });
+        ModelTrainer { result: result }
+    }
+
+    /// The trained segmenter, once it's ready
+    pub fn try_recv(&self) -> Option<ModelSegmenter<'static>> {
+        self.result.try_recv().ok()
+    }
+}
+
+// Frames either side used for the median the flux is compared to
+const MEDIAN_FRAMES: usize = 8;
+
+/// Segments at onsets, found as peaks in the spectral flux, inside the stretches where the
+/// input is loud enough to get through a gate. It needs no training, so it always has
+/// something to offer as long as there's sound.
+pub struct OnsetSegmenter {
+    config: OnsetConfig,
+    sample_rate: f64,
+}
+
+impl OnsetSegmenter {
+    pub fn new(config: OnsetConfig, sample_rate: f64) -> OnsetSegmenter {
+        OnsetSegmenter { config: config, sample_rate: sample_rate }
+    }
+
+    /// Spectral flux and RMS level in dBFS for each analysis frame
+    fn analyse(&self, samples: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let window = self.config.window.max(2);
+        let hop = self.config.hop.max(1);
+        if samples.len() < window {
+            return (Vec::new(), Vec::new());
+        }
+
+        let hann: Vec<f64> = (0..window)
+            .map(|n| 0.5 - 0.5 * (2. * PI * n as f64 / (window - 1) as f64).cos())
+            .collect();
+        let mut planner = FFTplanner::new(false);
+        let fft = planner.plan_fft(window);
+        let mut input = vec![Complex::zero(); window];
+        let mut output = vec![Complex::zero(); window];
+        let mut previous = vec![0f64; window / 2 + 1];
+
+        let frames = (samples.len() - window) / hop + 1;
+        let mut flux = Vec::with_capacity(frames);
+        let mut levels = Vec::with_capacity(frames);
+        for f in 0..frames {
+            let frame = &samples[(f * hop)..(f * hop + window)];
+            levels.push(rms_level(frame));
+
+            for ((x, s), w) in input.iter_mut().zip(frame.iter()).zip(hann.iter()) {
+                *x = Complex::new(s * w, 0.);
+            }
+            fft.process(&mut input, &mut output);
+            // Only rises in energy count, so notes dying away don't look like onsets
+            let mut sum = 0.;
+            for (bin, prev) in output.iter().zip(previous.iter_mut()) {
+                let magnitude = bin.norm();
+                sum += (magnitude - *prev).max(0.);
+                *prev = magnitude;
+            }
+            flux.push(sum);
+        }
+        (flux, levels)
+    }
+
+    /// Whether each frame is a peak in the flux well above its neighbourhood
+    fn onsets(&self, flux: &[f64]) -> Vec<bool> {
+        (0..flux.len()).map(|i| {
+            let lo = i.saturating_sub(MEDIAN_FRAMES);
+            let hi = (i + MEDIAN_FRAMES + 1).min(flux.len());
+            let mut neighbourhood = flux[lo..hi].to_vec();
+            neighbourhood.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+            let median = neighbourhood[neighbourhood.len() / 2];
+
+            flux[i] > median * self.config.sensitivity
+                && (i == 0 || flux[i] >= flux[i - 1])
+                && (i + 1 == flux.len() || flux[i] > flux[i + 1])
+        }).collect()
+    }
+
+    /// Adds the segment if it's long enough, cut into pieces if it's too long
+    fn push_segment(&self, start: usize, end: usize, segments: &mut Vec<(usize, usize)>) {
+        let min = (self.config.min_length * self.sample_rate) as usize;
+        let max = ((self.config.max_length * self.sample_rate) as usize).max(min).max(1);
+        if end <= start || end - start < min {
+            return;
+        }
+        let pieces = (end - start + max - 1) / max;
+        for p in 0..pieces {
+            segments.push((start + p * (end - start) / pieces, start + (p + 1) * (end - start) / pieces));
+        }
+    }
+}
+
+impl Segmenter for OnsetSegmenter {
+    fn segment(&self, sound: &Sound) -> Result<Vec<Segment>, String> {
+        let samples = sound.samples();
+        let (flux, levels) = self.analyse(samples);
+        let onsets = self.onsets(&flux[..]);
+        let hop = self.config.hop.max(1);
+        let min = (self.config.min_length * self.sample_rate) as usize;
+
+        // Gate with hysteresis, so a level hovering around the threshold doesn't chop the
+        // sound into slivers. Onsets split a segment only while the gate is open.
+        let mut segments = Vec::new();
+        let mut start: Option<usize> = None;
+        for i in 0..flux.len() {
+            let position = i * hop;
+            match start {
+                None => if levels[i] > self.config.gate_open {
+                    start = Some(position);
+                },
+                Some(s) => {
+                    // Flux is measured across the whole window, so an onset belongs to its middle
+                    let onset = position + self.config.window / 2;
+                    if levels[i] < self.config.gate_close {
+                        self.push_segment(s, position, &mut segments);
+                        start = None;
+                    } else if onsets[i] && onset >= s + min && onset < samples.len() {
+                        self.push_segment(s, onset, &mut segments);
+                        start = Some(onset);
+                    }
+                }
+            }
+        }
+        if let Some(s) = start {
+            self.push_segment(s, samples.len(), &mut segments);
+        }
+
+        if segments.is_empty() && !samples.is_empty() {
+            return Err("nothing rose above the gate".to_string());
+        }
+        Ok(segments.iter()
+           .map(|&(start, end)| (sample_duration(start, self.sample_rate), sample_duration(end, self.sample_rate)))
+           .collect())
+    }
+}
+
+/// Slices a sound into grains of the same length, one starting every hop, for granular
+/// reconstructions that don't care where the sound's own boundaries are
+pub struct GrainSegmenter {
+    config: GrainConfig,
+    sample_rate: f64,
+}
+
+impl GrainSegmenter {
+    pub fn new(config: GrainConfig, sample_rate: f64) -> GrainSegmenter {
+        GrainSegmenter { config: config, sample_rate: sample_rate }
+    }
+
+    /// Grain length and hop in samples
+    fn grain(&self) -> (usize, usize) {
+        let length = ((self.config.length * self.sample_rate).round() as usize).max(1);
+        let overlap = self.config.overlap.max(0.).min(0.95);
+        let hop = ((length as f64 * (1. - overlap)).round() as usize).max(1);
+        (length, hop)
+    }
+
+    /// Samples from the start of one grain to the next, if the grains overlap
+    pub fn overlap_hop(&self) -> Option<usize> {
+        let (length, hop) = self.grain();
+        if hop < length { Some(hop) } else { None }
+    }
+}
+
+impl Segmenter for GrainSegmenter {
+    fn segment(&self, sound: &Sound) -> Result<Vec<Segment>, String> {
+        let total = sound.samples().len();
+        let (length, hop) = self.grain();
+        // A sound shorter than a grain is a grain of its own; otherwise a partial grain at
+        // the end is left out
+        let grains: Vec<(usize, usize)> = if total < length {
+            if total > 0 { vec![(0, total)] } else { Vec::new() }
+        } else {
+            (0..((total - length) / hop + 1)).map(|n| (n * hop, n * hop + length)).collect()
+        };
+        Ok(grains.iter()
+           .map(|&(start, end)| (sample_duration(start, self.sample_rate), sample_duration(end, self.sample_rate)))
+           .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 8000.;
+
+    fn onset_segmenter() -> OnsetSegmenter {
+        OnsetSegmenter::new(OnsetConfig { window: 256, hop: 64, sensitivity: 1000., min_length: 0.01, ..OnsetConfig::default() }, RATE)
+    }
+
+    fn sound(samples: Vec<f64>) -> Sound {
+        Sound::from_samples(samples, RATE, None, None)
+    }
+
+    #[test]
+    fn onsets_are_peaks_above_the_median() {
+        let onsets = onset_segmenter().onsets(&[1., 1., 1., 10., 1., 1., 1.]);
+        assert_eq!(onsets, vec![false, false, false, true, false, false, false]);
+    }
+
+    #[test]
+    fn long_segments_are_cut_into_equal_pieces() {
+        let segmenter = OnsetSegmenter::new(OnsetConfig { max_length: 0.05, min_length: 0.01, ..OnsetConfig::default() }, RATE);
+        let mut segments = Vec::new();
+        segmenter.push_segment(0, 1000, &mut segments);
+        assert_eq!(segments, vec![(0, 333), (333, 666), (666, 1000)]);
+
+        segments.clear();
+        segmenter.push_segment(100, 150, &mut segments);
+        assert!(segments.is_empty());
+    }
+
+    #[test]
+    fn segments_only_what_gets_through_the_gate() {
+        let mut samples = vec![0.; 4000];
+        samples.extend((0..4000).map(|n| 0.5 * (2. * PI * 440. * n as f64 / RATE).sin()));
+        samples.extend(vec![0.; 4000]);
+        let segments = onset_segmenter().segment(&sound(samples)).unwrap();
+
+        assert!(!segments.is_empty());
+        let start = segments[0].0;
+        assert!(start >= sample_duration(4000 - 256, RATE) && start <= sample_duration(4000, RATE));
+        assert_eq!(segments[segments.len() - 1].1, sample_duration(8000, RATE));
+        for pair in segments.windows(2) {
+            assert_eq!(pair[0].1, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn silence_is_an_error() {
+        assert!(onset_segmenter().segment(&sound(vec![0.; 4000])).is_err());
+        assert_eq!(onset_segmenter().segment(&sound(Vec::new())), Ok(Vec::new()));
+    }
+
+    fn grains(length: f64, overlap: f64, samples: usize) -> Vec<Segment> {
+        let segmenter = GrainSegmenter::new(GrainConfig { length: length, overlap: overlap }, 1000.);
+        segmenter.segment(&Sound::from_samples(vec![0.; samples], 1000., None, None)).unwrap()
+    }
+
+    #[test]
+    fn grains_leave_out_a_partial_one_at_the_end() {
+        let segments = grains(0.1, 0., 1050);
+        assert_eq!(segments.len(), 10);
+        assert_eq!(segments[9], (sample_duration(900, 1000.), sample_duration(1000, 1000.)));
+    }
+
+    #[test]
+    fn overlapping_grains_start_every_hop() {
+        let segments = grains(0.1, 0.5, 1000);
+        assert_eq!(segments.len(), 19);
+        assert_eq!(segments[1], (sample_duration(50, 1000.), sample_duration(150, 1000.)));
+
+        let overlapping = GrainSegmenter::new(GrainConfig { length: 0.1, overlap: 0.5 }, 1000.);
+        assert_eq!(overlapping.overlap_hop(), Some(50));
+        let clamped = GrainSegmenter::new(GrainConfig { length: 0.1, overlap: 1. }, 1000.);
+        assert_eq!(clamped.overlap_hop(), Some(5));
+        let apart = GrainSegmenter::new(GrainConfig { length: 0.1, overlap: 0. }, 1000.);
+        assert_eq!(apart.overlap_hop(), None);
+    }
+
+    #[test]
+    fn a_short_sound_is_one_grain() {
+        assert_eq!(grains(0.1, 0., 30), vec![(sample_duration(0, 1000.), sample_duration(30, 1000.))]);
+        assert!(grains(0.1, 0., 0).is_empty());
+    }
+}
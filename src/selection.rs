@@ -0,0 +1,206 @@
+use super::*;
+
+/// How the segments standing in for the target are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelectionKind {
+    /// The closest segment to each target segment on its own
+    Nearest,
+    /// The sequence of segments with the least total cost, counting how well each one joins
+    /// on to the one before as well as how close it is to the target
+    Viterbi,
+}
+
+pub const SELECTIONS: [SelectionKind; 2] = [SelectionKind::Nearest, SelectionKind::Viterbi];
+
+impl SelectionKind {
+    /// Name used in commands and the config file
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SelectionKind::Nearest => "nearest",
+            SelectionKind::Viterbi => "viterbi",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SelectionKind> {
+        SELECTIONS.iter().find(|k| k.name() == name).cloned()
+    }
+}
+
+/// `[matching.selection]` section of the config file. Anything left out takes its default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SelectionConfig {
+    pub method: SelectionKind,
+    /// Closest segments to each target segment that the Viterbi search considers
+    pub candidates: usize,
+    /// Cost per unit of MFCC distance between the last frame of a segment and the first
+    /// frame of the next
+    pub join: f64,
+    /// Cost of following a segment with anything but the one after it in the same dictionary
+    pub jump: f64,
+}
+
+impl Default for SelectionConfig {
+    fn default() -> SelectionConfig {
+        SelectionConfig {
+            method: SelectionKind::Nearest,
+            candidates: 20,
+            join: 1.,
+            jump: 1.,
+        }
+    }
+}
+
+/// A segment that could stand in for a target segment
+pub struct Candidate<'a> {
+    pub dictionary: usize,
+    pub segment: usize,
+    pub features: &'a SegmentFeatures,
+    /// Weight of its dictionary, which target costs are divided by
+    pub weight: f64,
+}
+
+/// Chooses a unit for each target segment. Returns the index of each in `units` along with its
+/// target cost, or nothing if there are no units.
+pub fn select_units(target: &[SegmentFeatures], units: &[Candidate], matcher: &Matcher, config: &SelectionConfig) -> Vec<(usize, f64)> {
+    if units.is_empty() {
+        return Vec::new();
+    }
+    match config.method {
+        SelectionKind::Nearest => target.iter().map(|wanted| {
+            units.iter().enumerate()
+                .map(|(u, unit)| (u, matcher.distance(wanted, unit.features) / unit.weight))
+                .fold((0, ::std::f64::INFINITY), |best, c| if c.1 < best.1 { c } else { best })
+        }).collect(),
+        SelectionKind::Viterbi => viterbi(target, units, matcher, config),
+    }
+}
+
+fn viterbi(target: &[SegmentFeatures], units: &[Candidate], matcher: &Matcher, config: &SelectionConfig) -> Vec<(usize, f64)> {
+    // Only the closest few units to each target segment are in the running, which keeps the
+    // search down to candidates squared per segment
+    let candidates: Vec<Vec<(usize, f64)>> = target.iter().map(|wanted| {
+        let mut costs: Vec<(usize, f64)> = units.iter().enumerate()
+            .map(|(u, unit)| (u, matcher.distance(wanted, unit.features) / unit.weight))
+            .collect();
+        costs.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(::std::cmp::Ordering::Equal));
+        costs.truncate(config.candidates.max(1));
+        costs
+    }).collect();
+
+    // Lowest total cost of a path ending at each candidate, and the candidate before it
+    let mut totals: Vec<Vec<f64>> = Vec::with_capacity(target.len());
+    let mut back: Vec<Vec<usize>> = Vec::with_capacity(target.len());
+    for (t, choices) in candidates.iter().enumerate() {
+        if t == 0 {
+            totals.push(choices.iter().map(|&(_, cost)| cost).collect());
+            back.push(vec![0; choices.len()]);
+            continue;
+        }
+        let mut step_totals = Vec::with_capacity(choices.len());
+        let mut step_back = Vec::with_capacity(choices.len());
+        for &(u, cost) in choices.iter() {
+            let (from, total) = candidates[t - 1].iter().zip(totals[t - 1].iter()).enumerate()
+                .map(|(k, (&(previous, _), total))| (k, total + join_cost(&units[previous], &units[u], config)))
+                .fold((0, ::std::f64::INFINITY), |best, c| if c.1 < best.1 { c } else { best });
+            step_totals.push(total + cost);
+            step_back.push(from);
+        }
+        totals.push(step_totals);
+        back.push(step_back);
+    }
+
+    // Follow the cheapest path back from the end
+    let mut chosen = Vec::with_capacity(target.len());
+    let mut k = match totals.last() {
+        Some(last) => last.iter().enumerate()
+            .fold((0, ::std::f64::INFINITY), |best, (k, &total)| if total < best.1 { (k, total) } else { best }).0,
+        None => return chosen,
+    };
+    for t in (0..target.len()).rev() {
+        chosen.push(candidates[t][k]);
+        k = back[t][k];
+    }
+    chosen.reverse();
+    chosen
+}
+
+/// Cost of following `previous` with `next`. Nothing if they were next to each other to begin
+/// with, since they join up as they did in the source.
+fn join_cost(previous: &Candidate, next: &Candidate, config: &SelectionConfig) -> f64 {
+    if previous.dictionary == next.dictionary && next.segment == previous.segment + 1 {
+        return 0.;
+    }
+    config.join * euclidean(previous.features.last_frame(), next.features.first_frame()) + config.jump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(x: f64) -> SegmentFeatures {
+        let mut mfccs = vec![0.; NCOEFFS];
+        mfccs[0] = x;
+        SegmentFeatures { mean: mfccs.clone(), mfccs: mfccs, energy: 0., pitch: None }
+    }
+
+    fn candidate(dictionary: usize, segment: usize, features: &SegmentFeatures) -> Candidate {
+        Candidate { dictionary: dictionary, segment: segment, features: features, weight: 1. }
+    }
+
+    fn config(method: SelectionKind) -> SelectionConfig {
+        SelectionConfig { method: method, ..SelectionConfig::default() }
+    }
+
+    #[test]
+    fn nothing_to_choose_from() {
+        let target = vec![features(0.)];
+        assert!(select_units(&target, &[], &EuclideanMatcher, &config(SelectionKind::Viterbi)).is_empty());
+        let unit = features(0.);
+        assert!(select_units(&[], &[candidate(0, 0, &unit)], &EuclideanMatcher, &config(SelectionKind::Viterbi)).is_empty());
+    }
+
+    #[test]
+    fn nearest_divides_by_weight() {
+        let target = vec![features(0.)];
+        let (near, far) = (features(1.), features(1.5));
+        let mut units = vec![candidate(0, 0, &near), candidate(1, 0, &far)];
+        assert_eq!(select_units(&target, &units, &EuclideanMatcher, &config(SelectionKind::Nearest)), vec![(0, 1.)]);
+        units[1].weight = 2.;
+        assert_eq!(select_units(&target, &units, &EuclideanMatcher, &config(SelectionKind::Nearest)), vec![(1, 0.75)]);
+    }
+
+    #[test]
+    fn joins_cost_nothing_between_neighbours() {
+        let (a, b) = (features(0.), features(3.));
+        let config = SelectionConfig { join: 2., jump: 1., ..SelectionConfig::default() };
+        assert_eq!(join_cost(&candidate(0, 4, &a), &candidate(0, 5, &b), &config), 0.);
+        assert_eq!(join_cost(&candidate(0, 5, &a), &candidate(0, 4, &b), &config), 7.);
+        assert_eq!(join_cost(&candidate(0, 4, &a), &candidate(1, 5, &b), &config), 7.);
+    }
+
+    // The closest unit to the first target segment joins badly on to the only good match for
+    // the second, so the cheapest sequence starts with a worse match
+    fn units(features: &[SegmentFeatures]) -> Vec<Candidate> {
+        vec![candidate(0, 0, &features[0]), candidate(1, 0, &features[1]), candidate(0, 1, &features[2])]
+    }
+
+    #[test]
+    fn viterbi_follows_the_cheapest_path_back() {
+        let target = vec![features(0.), features(5.)];
+        let unit_features = vec![features(1.), features(0.), features(5.)];
+        let units = units(&unit_features);
+        assert_eq!(select_units(&target, &units, &EuclideanMatcher, &config(SelectionKind::Nearest)), vec![(1, 0.), (2, 0.)]);
+        assert_eq!(select_units(&target, &units, &EuclideanMatcher, &config(SelectionKind::Viterbi)), vec![(0, 1.), (2, 0.)]);
+    }
+
+    #[test]
+    fn viterbi_only_considers_the_closest_candidates() {
+        let target = vec![features(0.), features(5.)];
+        let unit_features = vec![features(1.), features(0.), features(5.)];
+        let units = units(&unit_features);
+        let config = SelectionConfig { method: SelectionKind::Viterbi, candidates: 1, ..SelectionConfig::default() };
+        assert_eq!(select_units(&target, &units, &EuclideanMatcher, &config), vec![(1, 0.), (2, 0.)]);
+    }
+}
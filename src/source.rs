@@ -0,0 +1,153 @@
+use bounded_spsc_queue::{self, Consumer};
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// Streams a WAV file into the dictionary in place of live input, in the same blocks the audio
+/// callback produces. Runs at `speed` times realtime, or as fast as the dictionary can take it
+/// if `speed` is 0.
+pub struct FileSource {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileSource {
+    /// The file is converted to `sample_rate` first, so it's analysed like live input would be
+    pub fn open<T>(path: &Path, speed: f64, sample_rate: f64) -> Result<(FileSource, Consumer<[f32; BLOCK_SIZE]>), Error<T>> {
+        let (samples, file_rate) = try!(read_wav_mono(path));
+        let samples: Vec<f32> = if file_rate as f64 != sample_rate {
+            let samples: Vec<f64> = samples.iter().map(|s| *s as f64).collect();
+            resample(&samples[..], file_rate as f64, sample_rate).iter().map(|s| *s as f32).collect()
+        } else {
+            samples
+        };
+        let (producer, consumer) = bounded_spsc_queue::make::<[f32; BLOCK_SIZE]>(65536);
+        let running = Arc::new(AtomicBool::new(true));
+        let block_duration = if speed > 0. {
+            Some(seconds_duration(BLOCK_SIZE as f64 / (sample_rate * speed)))
+        } else {
+            None
+        };
+
+        println!("streaming {} ({} samples)", path.display(), samples.len());
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || {
+            let mut next_block = Instant::now();
+            for chunk in samples.chunks(BLOCK_SIZE) {
+                let mut block = [0f32; BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+
+                // Unlike the audio callback there's no deadline here, so wait for room
+                // rather than dropping blocks
+                let mut pending = Some(block);
+                while let Some(block) = pending {
+                    if !thread_running.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    pending = producer.try_push(block);
+                    if pending.is_some() {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+
+                if let Some(duration) = block_duration {
+                    next_block += duration;
+                    let now = Instant::now();
+                    if next_block > now {
+                        thread::sleep(next_block - now);
+                    }
+                }
+            }
+            println!("finished streaming source file");
+        });
+
+        Ok((FileSource { running: running, thread: Some(thread) }, consumer))
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for FileSource {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound;
+
+    fn write_input(path: &Path, sample_rate: u32, frames: usize) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for n in 0..frames {
+            writer.write_sample(n as f32 / frames as f32).unwrap();
+            writer.write_sample(n as f32 / frames as f32).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    // Everything the source sends, once it has sent `count` blocks
+    fn receive(consumer: &Consumer<[f32; BLOCK_SIZE]>, count: usize) -> Vec<[f32; BLOCK_SIZE]> {
+        let mut blocks = Vec::new();
+        let mut waited = 0;
+        while blocks.len() < count {
+            assert!(waited < 5000, "only {} of {} blocks arrived", blocks.len(), count);
+            match consumer.try_pop() {
+                Some(block) => blocks.push(block),
+                None => {
+                    thread::sleep(Duration::from_millis(1));
+                    waited += 1;
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn streams_a_file_in_blocks() {
+        let dir = ScratchDir::new("file-source");
+        write_input(&dir.join("in.wav"), 8000, 100);
+        let (mut source, consumer) = FileSource::open::<()>(&dir.join("in.wav"), 0., 8000.).unwrap();
+        let blocks = receive(&consumer, 2);
+        source.stop();
+        assert!(consumer.try_pop().is_none());
+
+        // Mixed down to mono, with the last block filled out with silence
+        assert_eq!(blocks[0][10], 0.1);
+        assert_eq!(blocks[1][0], 0.64);
+        assert!(blocks[1][100 - BLOCK_SIZE..].iter().all(|s| *s == 0.));
+    }
+
+    #[test]
+    fn converts_the_file_to_the_sample_rate() {
+        let dir = ScratchDir::new("file-source-resample");
+        write_input(&dir.join("in.wav"), 4000, 100);
+        let (mut source, consumer) = FileSource::open::<()>(&dir.join("in.wav"), 0., 8000.).unwrap();
+        receive(&consumer, (200 + BLOCK_SIZE - 1) / BLOCK_SIZE);
+        source.stop();
+        assert!(consumer.try_pop().is_none());
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error() {
+        let dir = ScratchDir::new("file-source-missing");
+        assert!(FileSource::open::<()>(&dir.join("missing.wav"), 0., 8000.).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use soundsym::*;
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use super::*;
+
+/// Target used when the config file doesn't list any
+pub const DEFAULT_TARGET: &'static str = "inventing.wav";
+
+/// `[[targets]]` entry in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetConfig {
+    /// WAV file, relative to the assets folder unless it's absolute
+    pub path: PathBuf,
+    /// Audacity label track marking out the segments, used in place of the target segmenter
+    pub labels: Option<PathBuf>,
+    /// Name to show for it, the file name if left out
+    pub name: Option<String>,
+}
+
+impl TargetConfig {
+    pub fn new(path: PathBuf) -> TargetConfig {
+        TargetConfig { path: path, labels: None, name: None }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.path.file_stem().map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.path.display().to_string())
+        })
+    }
+
+    /// The same target with relative paths taken from `directory`
+    pub fn resolve(&self, directory: &Path) -> TargetConfig {
+        TargetConfig {
+            path: directory.join(&self.path),
+            labels: self.labels.as_ref().map(|labels| directory.join(labels)),
+            name: Some(self.name()),
+        }
+    }
+}
+
+/// A target loaded at the working rate and split into segments
+#[derive(Clone)]
+pub struct Target {
+    pub name: String,
+    pub sequence: Arc<SoundSequence>,
+    /// Features of each segment, which the library matches against
+    pub units: Vec<SegmentFeatures>,
+    /// Label of each segment, if it came from a label file
+    pub labels: Vec<Option<String>>,
+    pub features: FeatureMatrix,
+    /// Samples between the starts of the segments if they overlap, which reconstructions are
+    /// overlap-added at rather than laid end to end
+    pub hop: Option<usize>,
+}
+
+impl Target {
+    /// Loads the target at the working sample rate and splits it, at its labels if it has
+    /// any and otherwise with the target segmenter
+    pub fn load<T>(config: &TargetConfig, sample_rate: f64, segmentation: &SegmentationConfig) -> Result<Target, Error<T>> {
+        let (samples, file_rate) = try!(read_wav_mono(&config.path));
+        if file_rate as f64 != sample_rate {
+            println!("Resampling target from {} Hz to {} Hz", file_rate, sample_rate);
+        }
+        let samples: Vec<f64> = samples.iter().map(|s| *s as f64).collect();
+        let target = Sound::from_samples(resample(&samples[..], file_rate as f64, sample_rate), sample_rate, None, None);
+        println!("Source is {} samples", target.samples().len());
+
+        let (splits, labels): (Vec<Segment>, Vec<Option<String>>) = match config.labels {
+            Some(ref path) => {
+                let length = sample_duration(target.samples().len(), sample_rate);
+                let labelled = try!(read_labels(path));
+                labelled.into_iter()
+                    .filter(|&((start, _), _)| start < length)
+                    .map(|((start, end), label)| ((start, end.min(length)), label))
+                    .unzip()
+            }
+            None => {
+                let splits = try!(segment_target(&target, sample_rate, segmentation));
+                let labels = vec![None; splits.len()];
+                (splits, labels)
+            }
+        };
+
+        let hop = match (&config.labels, segmentation.target) {
+            (&None, SegmenterKind::Grain) => GrainSegmenter::new(segmentation.grain.clone(), sample_rate).overlap_hop(),
+            _ => None,
+        };
+
+        println!("Found {} splits in original sound", splits.len());
+        let dict = SoundDictionary::from_segments(&target, &splits[..]);
+        let units = dict.sounds.iter()
+            .map(|sound| SegmentFeatures::new(sound.samples(), sound.mfccs().clone(), sample_rate))
+            .collect();
+        let sequence = match hop {
+            // Played back to back, overlapping grains would repeat parts of the target, so
+            // the sequence only takes each one up to where the next starts
+            Some(hop) => {
+                let steps: Vec<Segment> = splits.iter()
+                    .map(|&(start, _)| (start, start + sample_duration(hop, sample_rate)))
+                    .collect();
+                SoundSequence::new(SoundDictionary::from_segments(&target, &steps[..]).sounds)
+            }
+            None => SoundSequence::new(dict.sounds),
+        };
+        let features = FeatureMatrix::from_mfccs(sequence.to_sound().mfccs());
+        Ok(Target {
+            name: config.name(),
+            sequence: Arc::new(sequence),
+            units: units,
+            labels: labels,
+            features: features,
+            hop: hop,
+        })
+    }
+}
+
+fn segment_target<T>(target: &Sound, sample_rate: f64, segmentation: &SegmentationConfig) -> Result<Vec<Segment>, Error<T>> {
+    match segmentation.target {
+        SegmenterKind::Model => Ok(try!(ModelSegmenter::train(Cow::Borrowed(target), DEFAULT_THRESHOLD, DEFAULT_DEPTH).segment(target))),
+        SegmenterKind::Onset => Ok(try!(OnsetSegmenter::new(segmentation.onset.clone(), sample_rate).segment(target))),
+        SegmenterKind::Grain => Ok(try!(GrainSegmenter::new(segmentation.grain.clone(), sample_rate).segment(target))),
+    }
+}
+
+/// Reads an Audacity label track: a start and end in seconds and an optional label on each
+/// line, separated by tabs. Lines starting with a backslash hold frequency ranges and are
+/// skipped.
+pub fn read_labels<T>(path: &Path) -> Result<Vec<(Segment, Option<String>)>, Error<T>> {
+    let reader = BufReader::new(try!(File::open(path)));
+    let mut labels = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        if line.trim().is_empty() || line.starts_with('\\') {
+            continue;
+        }
+        let mut fields = line.splitn(3, '\t');
+        let start = fields.next().and_then(|s| s.trim().parse::<f64>().ok());
+        let end = fields.next().and_then(|s| s.trim().parse::<f64>().ok());
+        let label = fields.next().map(|l| l.trim())
+            .and_then(|l| if l.is_empty() { None } else { Some(l.to_string()) });
+        match (start, end) {
+            (Some(start), Some(end)) if start >= 0. && end > start => {
+                labels.push(((seconds_duration(start), seconds_duration(end)), label));
+            }
+            _ => return Err(Error::String(format!("{} line {}: expected a start and end in seconds", path.display(), n + 1))),
+        }
+    }
+    Ok(labels)
+}
+
+/// The targets listed in the config file, the one being reconstructed and any others that
+/// have been loaded so far
+pub struct Playlist {
+    configs: Vec<TargetConfig>,
+    targets: Vec<Option<Target>>,
+    // Why each target that couldn't be loaded failed
+    failed: Vec<Option<String>>,
+    current: usize,
+    // Asked for before it was ready
+    pending: Option<usize>,
+    loaders: Vec<TargetLoader>,
+    // What the targets are loaded with, kept for trying failed ones again
+    sample_rate: f64,
+    segmentation: SegmentationConfig,
+}
+
+impl Playlist {
+    /// Loads the first target straight away and the rest in the background
+    pub fn load<T>(configs: Vec<TargetConfig>, sample_rate: f64, segmentation: &SegmentationConfig) -> Result<Playlist, Error<T>> {
+        let mut playlist = Playlist {
+            configs: configs,
+            targets: Vec::new(),
+            failed: Vec::new(),
+            current: 0,
+            pending: None,
+            loaders: Vec::new(),
+            sample_rate: sample_rate,
+            segmentation: segmentation.clone(),
+        };
+        try!(playlist.reload(sample_rate, segmentation));
+        Ok(playlist)
+    }
+
+    /// Loads the current target again and starts on the rest in the background, for when
+    /// anything that decides how they're split has changed. Nothing changes if the current
+    /// target can't be loaded.
+    pub fn reload<T>(&mut self, sample_rate: f64, segmentation: &SegmentationConfig) -> Result<(), Error<T>> {
+        let target = try!(Target::load(&self.configs[self.current], sample_rate, segmentation));
+        let n = self.configs.len();
+        self.targets = (0..n).map(|_| None).collect();
+        self.targets[self.current] = Some(target);
+        self.failed = vec![None; n];
+        self.pending = None;
+        self.sample_rate = sample_rate;
+        self.segmentation = segmentation.clone();
+        // The ones after the current target are the likeliest to be wanted next
+        let order = (1..n).map(|i| (self.current + i) % n).collect();
+        self.loaders = vec![TargetLoader::spawn(&self.configs[..], order, sample_rate, segmentation.clone())];
+        Ok(())
+    }
+
+    pub fn target(&self) -> &Target {
+        self.targets[self.current].as_ref().expect("the current target is always loaded")
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Switches to the target at `idx` if it's ready, otherwise as soon as it is. A target
+    /// that failed to load is tried again. Returns whether it switched straight away.
+    pub fn select(&mut self, idx: usize) -> Result<bool, String> {
+        if idx >= self.configs.len() {
+            return Err(format!("there are only {} targets", self.configs.len()));
+        }
+        if self.targets[idx].is_some() {
+            self.current = idx;
+            self.pending = None;
+            Ok(true)
+        } else {
+            if let Some(e) = self.failed[idx].take() {
+                // Whatever was wrong may have been put right since
+                println!("{} could not be loaded ({}), trying again", self.configs[idx].name(), e);
+                self.loaders.push(TargetLoader::spawn(&self.configs[..], vec![idx], self.sample_rate, self.segmentation.clone()));
+            }
+            println!("switching to {} once it's loaded", self.configs[idx].name());
+            self.pending = Some(idx);
+            Ok(false)
+        }
+    }
+
+    /// Takes in targets that have finished loading, switching to one that was asked for
+    /// earlier. Returns whether any finished.
+    pub fn poll(&mut self) -> bool {
+        let mut finished = false;
+        loop {
+            let next = self.loaders.iter().filter_map(|l| l.try_recv()).next();
+            let (idx, result) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            finished = true;
+            match result {
+                Ok(target) => {
+                    println!("target {} is ready", target.name);
+                    self.targets[idx] = Some(target);
+                    if self.pending == Some(idx) {
+                        self.current = idx;
+                        self.pending = None;
+                    }
+                }
+                Err(e) => {
+                    println!("could not load target {}: {}", self.configs[idx].name(), e);
+                    self.failed[idx] = Some(e);
+                    if self.pending == Some(idx) {
+                        self.pending = None;
+                    }
+                }
+            }
+        }
+        finished
+    }
+
+    pub fn info(&self) -> Vec<TargetInfo> {
+        self.configs.iter().zip(self.targets.iter()).map(|(config, target)| TargetInfo {
+            name: config.name(),
+            ready: target.is_some(),
+        }).collect()
+    }
+}
+
+/// What the frontend shows of each target in the playlist
+#[derive(Debug, Clone)]
+pub struct TargetInfo {
+    pub name: String,
+    /// Loaded and split, so switching to it is immediate
+    pub ready: bool,
+}
+
+/// Loads and splits targets on a thread of its own, one after another, so they're ready to
+/// switch to by the time they're wanted. Dropping it stops the thread after the target it's
+/// working on.
+pub struct TargetLoader {
+    results: mpsc::Receiver<(usize, Result<Target, String>)>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TargetLoader {
+    /// Loads the targets at the given positions in the playlist, in that order
+    pub fn spawn(playlist: &[TargetConfig], order: Vec<usize>, sample_rate: f64, segmentation: SegmentationConfig) -> TargetLoader {
+        let (prod, results) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let stop = cancelled.clone();
+        let playlist = playlist.to_vec();
+        thread::spawn(move || {
+            for idx in order {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let result = Target::load::<()>(&playlist[idx], sample_rate, &segmentation)
+                    .map_err(|e| e.to_string());
+                if prod.send((idx, result)).is_err() {
+                    break;
+                }
+            }
+        });
+        TargetLoader { results: results, cancelled: cancelled }
+    }
+
+    /// A target that's finished loading, if there is one
+    pub fn try_recv(&self) -> Option<(usize, Result<Target, String>)> {
+        self.results.try_recv().ok()
+    }
+}
+
+impl Drop for TargetLoader {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn labels(name: &str, contents: &str) -> Result<Vec<(Segment, Option<String>)>, Error<()>> {
+        let dir = ScratchDir::new(name);
+        let path = dir.join("labels.txt");
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        read_labels(&path)
+    }
+
+    #[test]
+    fn reads_audacity_labels() {
+        let labels = labels("labels-audacity", "0.000000\t0.500000\tin\n\
+                                           \\\t100.0\t2000.0\n\
+                                           \n\
+                                           0.5\t1.25\n\
+                                           1.25\t2\t  the beginning \n").unwrap();
+        assert_eq!(labels, vec![
+            ((seconds_duration(0.), seconds_duration(0.5)), Some("in".to_string())),
+            ((seconds_duration(0.5), seconds_duration(1.25)), None),
+            ((seconds_duration(1.25), seconds_duration(2.)), Some("the beginning".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert!(labels("labels-no-end", "0.5\n").is_err());
+        assert!(labels("labels-backwards", "1\t0.5\tx\n").is_err());
+        assert!(labels("labels-negative", "-1\t0.5\n").is_err());
+        assert!(labels("labels-words", "start\tend\n").is_err());
+    }
+}